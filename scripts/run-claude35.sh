@@ -11,7 +11,7 @@ function run(){
   cargo run --release -- --no-stage -q $@
 }
 
-OPT=" --log-file logfile.log --max-tokens 4096  -a /home/qxxu/tymcrat/.anthropic_api_key --model claude-3-5-sonnet-20240620  --show-openai-stat --show-program-size --real-time --show-time "
+OPT=" --log-file logfile.log --max-tokens 4096 --provider anthropic -a /home/qxxu/tymcrat/.anthropic_api_key --model claude-3-5-sonnet-20240620  --show-openai-stat --show-program-size --real-time --show-time --overrides scripts/overrides.json "
 
 if [ $1 -eq 0 ]; then
   OPT+="--no-candidate "
@@ -11,7 +11,7 @@ function run(){
   cargo run --release -- --no-stage -q $@
 }
 
-OPT="-a .openai_api_key --real-time --show-time"
+OPT="-a .openai_api_key --overrides scripts/overrides.json --real-time --show-time"
 
 if [ $1 -eq 0 ]; then
   OPT+="--no-candidate "
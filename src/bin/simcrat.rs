@@ -1,21 +1,75 @@
-use std::{fs::File, io::Write, time::Instant};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    time::Instant,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use etrace::some_or;
 use simcrat::*;
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Provider {
+    #[value(name = "openai")]
+    OpenAI,
+    Anthropic,
+    Local,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Operations on the LLM response cache selected by the db/cache options
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Translate the strongly connected component of a function again from the checkpoint and
+    /// rewrite the output
+    Retranslate {
+        /// C or Rust name of the function
+        #[arg(long)]
+        item: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Write every cache entry to a JSONL file
+    Export { file: String },
+    /// Insert every entry of a JSONL file into the cache
+    Import { file: String },
+    /// Show the number of entries and stored tokens per request kind
+    Stats,
+    /// Copy every entry whose key has no model tag under a key tagged with the given model
+    Migrate {
+        #[arg(long)]
+        model: String,
+        #[arg(long, default_value_t = llm_client::PROMPT_VERSION)]
+        version: u32,
+    },
+}
+
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long)]
     log_file: Option<String>,
 
-    // #[arg(long)]
-    // local_url: Option<String>,
+    #[arg(long, value_enum, default_value_t = Provider::OpenAI)]
+    provider: Provider,
+    #[arg(long)]
+    url: Option<String>,
+    #[arg(long)]
+    record: Option<String>,
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<String>,
     #[arg(short, long)]
     api_key_file: Option<String>,
     #[arg(short, long)]
     model: Option<String>,
-    #[arg(long)]
-    max_tokens: usize,
+    #[arg(long, required = true)]
+    max_tokens: Option<usize>,
 
     #[arg(long)]
     db_name: Option<String>,
@@ -25,6 +79,8 @@ struct Args {
     db_port: Option<String>,
     #[arg(long)]
     db_password: Option<String>,
+    #[arg(long, conflicts_with = "db_name")]
+    cache_file: Option<String>,
 
     #[arg(long)]
     no_candidate: bool,
@@ -36,6 +92,16 @@ struct Args {
     no_stage: bool,
     #[arg(long)]
     num_signatures: Option<usize>,
+    #[arg(long)]
+    reduce_unsafe: bool,
+
+    #[arg(long)]
+    overrides: Option<String>,
+
+    #[arg(long)]
+    checkpoint: Option<String>,
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
 
     #[arg(long)]
     parsing_only: bool,
@@ -52,10 +118,14 @@ struct Args {
     // show_per_stage: bool,
     #[arg(long)]
     show_openai_stat: bool,
+    #[arg(long)]
+    show_cache_stat: bool,
     // #[arg(long)]
     // show_signature: bool,
     #[arg(long)]
     show_type: bool,
+    #[arg(long, requires = "reduce_unsafe")]
+    show_unsafe: bool,
 
     #[arg(short, long)]
     detail: bool,
@@ -64,7 +134,20 @@ struct Args {
 
     #[arg(short, long)]
     output: Option<String>,
-    input: String,
+    #[arg(long)]
+    project: Option<String>,
+    #[arg(long, requires = "project")]
+    modules: bool,
+    #[arg(long)]
+    diff_test: bool,
+    #[arg(long, requires = "diff_test")]
+    diff_inputs: Option<String>,
+    #[arg(long)]
+    report: Option<String>,
+    #[arg(long)]
+    provenance: Option<String>,
+    #[arg(required = true)]
+    input: Option<String>,
 }
 
 #[tokio::main]
@@ -87,10 +170,21 @@ async fn main() {
         host: args.db_host,
         port: args.db_port,
         password: args.db_password,
+        file: args.cache_file,
         real_time: args.real_time,
     };
+
+    let retranslate = match args.command {
+        Some(Command::Cache(command)) => {
+            run_cache_command(command, db_conf).await;
+            return;
+        }
+        Some(Command::Retranslate { item }) => Some(item),
+        None => None,
+    };
+
     let config = translation::Config {
-        max_tokens: args.max_tokens,
+        max_tokens: args.max_tokens.unwrap(),
         try_multiple_signatures: !args.no_candidate,
         num_signatures: args.num_signatures.unwrap_or(3),
         provide_signatures: !args.no_augmentation,
@@ -101,11 +195,54 @@ async fn main() {
 
     let start = Instant::now();
 
-    let prog = c_parser::Program::from_compile_commands(&args.input);
-    let client: Box<dyn llm_client::LanguageModel + Send + Sync> = Box::new(
-        llm_client::openai::OpenAIClient::new(model, api_key, db_conf),
-    );
+    let prog = c_parser::Program::from_compile_commands(args.input.expect("no input file"));
+    let client: Box<dyn llm_client::LanguageModel + Send + Sync> = if let Some(replay) = args.replay
+    {
+        Box::new(llm_client::replay::ReplayClient::replay(&replay))
+    } else {
+        let client: Box<dyn llm_client::LanguageModel + Send + Sync> = match args.provider {
+            Provider::OpenAI => Box::new(llm_client::openai::OpenAIClient::new(
+                model, api_key, args.url, db_conf,
+            )),
+            Provider::Anthropic => Box::new(llm_client::anthropic::AnthroClient::new(
+                model, api_key, args.url, db_conf,
+            )),
+            Provider::Local => {
+                let url = args.url.expect("--url is required for the local provider");
+                Box::new(llm_client::local::LocalClient::new(url, model, db_conf))
+            }
+        };
+        if let Some(record) = args.record {
+            Box::new(llm_client::replay::ReplayClient::record(client, &record))
+        } else {
+            client
+        }
+    };
     let mut translator = translation::Translator::new(&prog, client, config);
+    if args.provenance.is_some() {
+        translator.record_provenance();
+    }
+    if let Some(overrides) = args.overrides {
+        let result = overrides::Overrides::from_file(&overrides).and_then(|o| {
+            translator
+                .set_overrides(o)
+                .map_err(|e| format!("{}: {}", overrides, e))
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(checkpoint) = args.checkpoint {
+        translator.set_checkpoint(checkpoint);
+        if retranslate.is_some() {
+            assert!(translator.resume(), "no checkpoint to retranslate from");
+        } else if args.resume && !translator.resume() && !args.quiet {
+            println!("No checkpoint to resume from");
+        }
+    } else if retranslate.is_some() {
+        panic!("--checkpoint is required to retranslate");
+    }
 
     if args.parsing_only {
         if args.show_program_size {
@@ -114,11 +251,23 @@ async fn main() {
         return;
     }
 
-    translator.translate_names().await;
-    translator.translate_types().await;
-    translator.translate_variables().await;
-    translator.translate_protos().await;
-    translator.translate_functions().await;
+    if let Some(item) = retranslate {
+        translator.retranslate(&item).await;
+    } else {
+        translator.translate_names().await;
+        translator.translate_types().await;
+        translator.translate_macros().await;
+        translator.translate_variables().await;
+        translator.translate_protos().await;
+        translator.translate_functions().await;
+    }
+    if args.reduce_unsafe {
+        translator.reduce_unsafe().await;
+    }
+
+    if args.diff_test {
+        translator.test_behavior(args.diff_inputs.as_deref());
+    }
 
     if args.show_program_size {
         translator.show_information();
@@ -132,16 +281,87 @@ async fn main() {
         translator.show_openai_stat();
     }
 
+    if args.show_cache_stat {
+        translator.show_cache_stat();
+    }
+
     if args.show_type {
         translator.show_type();
     }
 
+    if args.show_unsafe {
+        translator.show_unsafe();
+    }
+
     if args.show_time {
         println!("{}", start.elapsed().as_secs_f32());
     }
 
+    if let Some(report) = args.report {
+        translator.write_report(&report);
+    }
+
+    if let Some(provenance) = args.provenance {
+        translator.write_provenance(&provenance);
+    }
+
     if let Some(output) = args.output {
         let mut f = File::create(output).unwrap();
         f.write_all(translator.code().as_bytes()).unwrap();
     }
+
+    if let Some(project) = args.project {
+        let files = if args.modules {
+            translator.module_files()
+        } else {
+            vec![("main.rs".to_string(), translator.code())]
+        };
+        project::write_project(project, &files).unwrap();
+    }
+}
+
+async fn run_cache_command(command: CacheCommand, db_conf: llm_client::cache::DbConfig) {
+    let cache: llm_client::cache::Cache<serde_json::Value, llm_client::cache::RawVal> =
+        llm_client::cache::Cache::new(db_conf);
+    match command {
+        CacheCommand::Export { file } => {
+            let mut f = File::create(file).unwrap();
+            for (key, val) in cache.entries().await {
+                let data = serde_json::json!({ "_id": key, "val": val });
+                writeln!(f, "{}", data).unwrap();
+            }
+        }
+        CacheCommand::Import { file } => {
+            let f = File::open(file).unwrap();
+            for line in BufReader::new(f).lines() {
+                let line = line.unwrap();
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut data: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let key = data["_id"].take();
+                let val = llm_client::cache::RawVal(data["val"].take());
+                cache.insert(key, val).await;
+            }
+        }
+        CacheCommand::Stats => {
+            let entries = cache.entries().await;
+            let stats = llm_client::cache::kind_stats(&entries);
+            let tokens: usize = stats.values().map(|stat| stat.tokens).sum();
+            println!("entries {}", entries.len());
+            println!("tokens {}", tokens);
+            for (kind, stat) in stats {
+                println!("{} {} {}", kind, stat.entries, stat.tokens);
+            }
+        }
+        CacheCommand::Migrate { model, version } => {
+            let mut migrated = 0;
+            for (key, val) in cache.entries().await {
+                let key = some_or!(llm_client::cache::tag_key(&key, &model, version), continue);
+                cache.insert(key, val).await;
+                migrated += 1;
+            }
+            println!("migrated {}", migrated);
+        }
+    }
 }
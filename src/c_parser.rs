@@ -1,12 +1,14 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     fmt,
-    fs::File,
+    fs::{self, File},
     io::BufReader,
+    ops::Range,
     path::{Path, PathBuf},
     process::Command,
 };
 
+use etrace::some_or;
 use lang_c::{
     ast::*,
     driver::{self, Config, Parse},
@@ -17,7 +19,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{self, FunTySig, Type};
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
 pub enum TypeSort {
     Typedef,
     Struct,
@@ -79,6 +81,12 @@ pub struct TypeDependency<'ast> {
     pub span: Span,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Reference<'ast> {
+    pub symbol: &'ast str,
+    pub span: Span,
+}
+
 #[derive(Debug)]
 pub struct Typedef<'ast> {
     pub declaration: &'ast Node<Declaration>,
@@ -90,6 +98,7 @@ pub struct Typedef<'ast> {
     pub is_struct_alias: bool,
     pub dependencies: Vec<TypeDependency<'ast>>,
     pub path: &'ast str,
+    pub comment: Option<&'ast str>,
 }
 
 #[derive(Debug)]
@@ -100,6 +109,7 @@ pub struct Struct<'ast> {
     pub struct_type: &'ast Node<StructType>,
     pub dependencies: Vec<TypeDependency<'ast>>,
     pub path: &'ast str,
+    pub comment: Option<&'ast str>,
 }
 
 #[derive(Debug)]
@@ -108,6 +118,7 @@ pub struct Enum<'ast> {
     pub name: &'ast str,
     pub enum_type: &'ast Node<EnumType>,
     pub path: &'ast str,
+    pub comment: Option<&'ast str>,
 }
 
 #[derive(Debug)]
@@ -119,8 +130,9 @@ pub struct Variable<'ast> {
     pub identifier: &'ast Node<Identifier>,
     pub declarator: &'ast Node<InitDeclarator>,
     pub type_dependencies: Vec<TypeDependency<'ast>>,
-    pub dependencies: Vec<&'ast Node<Identifier>>,
+    pub dependencies: Vec<Reference<'ast>>,
     pub path: &'ast str,
+    pub comment: Option<&'ast str>,
 }
 
 #[derive(Debug)]
@@ -130,10 +142,70 @@ pub struct Function<'ast> {
     pub type_signature: FunTySig,
     pub definition: &'ast Node<FunctionDefinition>,
     pub type_dependencies: Vec<TypeDependency<'ast>>,
-    pub dependencies: Vec<&'ast Node<Identifier>>,
-    pub callees: Vec<&'ast Node<Identifier>>,
+    pub dependencies: Vec<Reference<'ast>>,
+    pub callees: Vec<Reference<'ast>>,
     pub calls: usize,
     pub path: &'ast str,
+    pub comment: Option<&'ast str>,
+}
+
+/// A macro definition, as printed by `-dD`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Option<Vec<String>>,
+    pub body: String,
+    pub path: String,
+}
+
+impl Macro {
+    fn parse(definition: &str, path: &str) -> Option<Self> {
+        let end = definition
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(definition.len());
+        let (name, rest) = definition.split_at(end);
+        let (params, body) = if let Some(rest) = rest.strip_prefix('(') {
+            let (params, body) = rest.split_once(')')?;
+            let params = params
+                .split(',')
+                .map(|param| param.trim().to_string())
+                .filter(|param| !param.is_empty())
+                .collect();
+            (Some(params), body)
+        } else {
+            (None, rest)
+        };
+        Some(Self {
+            name: name.to_string(),
+            params,
+            body: body.trim().to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    pub fn identifiers(&self) -> BTreeSet<&str> {
+        identifiers(&self.body)
+    }
+
+    pub fn to_string(&self, names: &BTreeMap<&str, &str>) -> String {
+        let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        let mut body = String::new();
+        let mut rest = self.body.as_str();
+        while let Some(start) = rest.find(is_ident) {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+            let word = &rest[..end];
+            body.push_str(names.get(word).copied().unwrap_or(word));
+            rest = &rest[end..];
+        }
+        body.push_str(rest);
+        let name = names.get(self.name.as_str()).copied().unwrap_or(&self.name);
+        match &self.params {
+            Some(params) => format!("#define {}({}) {}", name, params.join(", "), body),
+            None => format!("#define {} {}", name, body),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -144,6 +216,11 @@ pub struct Program {
     pub variable_set: BTreeSet<String>,
     pub proto_set: BTreeSet<String>,
     pub function_set: BTreeSet<String>,
+    pub qualified: BTreeMap<String, BTreeMap<String, String>>,
+    pub macros: BTreeMap<String, Macro>,
+    pub macro_uses: BTreeMap<String, BTreeSet<String>>,
+    pub constant_uses: BTreeMap<String, Vec<(Span, String)>>,
+    pub comments: BTreeMap<String, BTreeMap<usize, String>>,
 }
 
 impl Program {
@@ -155,16 +232,38 @@ impl Program {
             .into_iter()
             .flat_map(|command| command.preprocess())
             .collect();
+        assert!(!files.is_empty(), "no C file to translate");
         Self::new(files)
     }
 
+    #[cfg(test)]
+    pub(crate) fn from_code(code: &str) -> Self {
+        Self::from_files(&[("a.c", code)])
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_files(files: &[(&str, &str)]) -> Self {
+        let files = files.iter().map(|(path, code)| Preprocessed {
+            path: PathBuf::from(path),
+            directory: PathBuf::new(),
+            code: code.to_string(),
+            long_code: code.to_string(),
+        });
+        Self::new(files.collect())
+    }
+
     fn new(files: Vec<Preprocessed>) -> Self {
         let mut parses = BTreeMap::new();
         let mut typedef_set = BTreeSet::new();
         let mut struct_set = BTreeSet::new();
-        let mut variable_set = BTreeSet::new();
-        let mut proto_set = BTreeSet::new();
-        let mut function_set = BTreeSet::new();
+        let mut variables = vec![];
+        let mut protos = vec![];
+        let mut functions = vec![];
+        let mut macros: BTreeMap<String, Option<Macro>> = BTreeMap::new();
+        let mut identifier_uses = vec![];
+        let mut constant_uses = vec![];
+        let mut comments = BTreeMap::new();
+        let mut sources: BTreeMap<PathBuf, Option<Vec<String>>> = BTreeMap::new();
 
         let config = Config::with_gcc();
 
@@ -180,10 +279,110 @@ impl Program {
             let lib_spans = find_lib_spans(&parse);
             let is_lib = |span: Span| lib_spans.iter().any(|s| overlap(*s, span));
 
-            for decl in &parse.unit.0 {
+            let origins = line_origins(&parse.source);
+            // all the macros of the file, including those of system headers, to expand constants
+            let mut file_macros = BTreeMap::new();
+            for (line, origin) in parse.source.lines().zip(&origins) {
+                let (origin_path, _, system) = some_or!(origin, continue);
+                if let Some(name) = line.strip_prefix("#undef ") {
+                    file_macros.remove(name.trim());
+                    continue;
+                }
+                let definition = some_or!(line.strip_prefix("#define "), continue);
+                let m = some_or!(Macro::parse(definition, origin_path), continue);
+                file_macros.insert(m.name.clone(), m.clone());
+                if *system || (origin_path.starts_with('<') && *origin_path != "<command-line>") {
+                    continue;
+                }
+                if m.body.is_empty() {
+                    continue;
+                }
+                match macros.get(&m.name) {
+                    None => {
+                        macros.insert(m.name.clone(), Some(m));
+                    }
+                    Some(Some(old)) if old.params != m.params || old.body != m.body => {
+                        tracing::warn!(
+                            "macro {} has different definitions; not translating it",
+                            m.name
+                        );
+                        macros.insert(m.name.clone(), None);
+                    }
+                    _ => (),
+                }
+            }
+            let line_starts: Vec<_> = std::iter::once(0)
+                .chain(parse.source.match_indices('\n').map(|(i, _)| i + 1))
+                .collect();
+            // the identifiers in the unpreprocessed code of a declaration, which include the
+            // macros it uses, the constants expanded in the declaration and the comment before it
+            let mut original = |decl: Span| {
+                let start = parse.source[..decl.start].matches('\n').count();
+                let end = parse.source[..decl.end].matches('\n').count();
+                let mut code = String::new();
+                let mut constants = vec![];
+                let mut comment = None;
+                for (i, origin) in origins.iter().enumerate().take(end + 1).skip(start) {
+                    let (origin_path, line_no, _) = some_or!(origin, continue);
+                    let lines = sources
+                        .entry(file.directory.join(origin_path))
+                        .or_insert_with_key(|path| {
+                            let content = fs::read_to_string(path).ok()?;
+                            Some(content.lines().map(|line| line.to_string()).collect())
+                        });
+                    if code.is_empty() {
+                        if let (Some(lines), Some(i)) = (lines.as_ref(), line_no.checked_sub(1)) {
+                            comment = leading_comment(lines, i);
+                        }
+                    }
+                    let line = line_no.checked_sub(1).and_then(|i| lines.as_ref()?.get(i));
+                    let line = some_or!(line, continue);
+                    code.push_str(line);
+                    code.push('\n');
+
+                    let line_start = line_starts[i];
+                    let line_end = line_starts.get(i + 1).map_or(parse.source.len(), |e| e - 1);
+                    let preprocessed = &parse.source[line_start..line_end];
+                    for (range, name) in expanded_constants(line, preprocessed, &file_macros) {
+                        let span = Span::span(line_start + range.start, line_start + range.end);
+                        if decl.start <= span.start && span.end <= decl.end {
+                            constants.push((span, name));
+                        }
+                    }
+                }
+                let ids: BTreeSet<_> = identifiers(&code)
+                    .into_iter()
+                    .map(|id| id.to_string())
+                    .collect();
+                (ids, constants, comment)
+            };
+            let short = parses.get(path).unwrap();
+            let aligned = short.unit.0.len() == parse.unit.0.len();
+
+            for (k, decl) in parse.unit.0.iter().enumerate() {
                 if is_lib(decl.span) {
                     continue;
                 }
+                let (ids, constants, comment) = original(decl.span);
+                // the spans in the code without line markers and definitions
+                let (constants, comment) = if aligned {
+                    let short_span = short.unit.0[k].span;
+                    let long = (parse.source.as_str(), decl.span);
+                    let short = (short.source.as_str(), short_span);
+                    let constants = corresponding_spans(long, short, constants);
+                    (
+                        constants,
+                        comment.map(|comment| (short_span.start, comment)),
+                    )
+                } else {
+                    (vec![], None)
+                };
+                if let Some((start, comment)) = comment {
+                    comments
+                        .entry(path.to_string())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(start, comment);
+                }
                 match &decl.node {
                     ExternalDeclaration::Declaration(decl) => {
                         if is_typedef(&decl.node) {
@@ -192,12 +391,24 @@ impl Program {
                             }
                         } else {
                             let extrn = is_extern(&decl.node);
+                            let stat = is_static(&decl.node.specifiers);
                             for decl in &decl.node.declarators {
                                 let name = declarator_name(&decl.node.declarator.node);
+                                let symbol = (path.to_string(), name.to_string(), stat);
                                 if is_function_proto(&decl.node) {
-                                    proto_set.insert(name.to_string());
+                                    protos.push(symbol);
                                 } else if !extrn {
-                                    variable_set.insert(name.to_string());
+                                    identifier_uses.push((
+                                        symbol.0.clone(),
+                                        symbol.1.clone(),
+                                        ids.clone(),
+                                    ));
+                                    constant_uses.push((
+                                        symbol.0.clone(),
+                                        symbol.1.clone(),
+                                        constants.clone(),
+                                    ));
+                                    variables.push(symbol);
                                 }
                             }
                         }
@@ -229,26 +440,120 @@ impl Program {
                             .node
                             .name
                             .clone();
-                        function_set.insert(name.to_string());
+                        let stat = is_static(&func.node.specifiers);
+                        identifier_uses.push((path.to_string(), name.clone(), ids));
+                        constant_uses.push((path.to_string(), name.clone(), constants));
+                        functions.push((path.to_string(), name, stat));
                     }
                     _ => (),
                 }
             }
         }
 
-        for f in &function_set {
-            variable_set.remove(f);
-            proto_set.remove(f);
+        // an internal-linkage name is qualified when another file defines the same name
+        let mut linkage: BTreeMap<&str, (BTreeSet<&str>, bool)> = BTreeMap::new();
+        for (path, name, stat) in variables.iter().chain(&functions) {
+            let (paths, global) = linkage.entry(name).or_default();
+            if *stat {
+                paths.insert(path);
+            } else {
+                *global = true;
+            }
+        }
+        let mut qualified: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        for (name, (paths, global)) in linkage {
+            if paths.len() > 1 || (global && !paths.is_empty()) {
+                for path in paths {
+                    qualified
+                        .entry(path.to_string())
+                        .or_default()
+                        .insert(name.to_string(), format!("{}@{}", name, path));
+                }
+            }
         }
 
-        Self {
+        let mut this = Self {
             parses,
             typedef_set,
             struct_set,
-            variable_set,
-            proto_set,
-            function_set,
+            variable_set: BTreeSet::new(),
+            proto_set: BTreeSet::new(),
+            function_set: BTreeSet::new(),
+            qualified,
+            macros: BTreeMap::new(),
+            macro_uses: BTreeMap::new(),
+            constant_uses: BTreeMap::new(),
+            comments,
+        };
+        let symbols = |v: Vec<(String, String, bool)>| -> BTreeSet<String> {
+            v.iter()
+                .map(|(path, name, _)| this.symbol(path, name).to_string())
+                .collect()
+        };
+        let mut variable_set = symbols(variables);
+        let mut proto_set = symbols(protos);
+        let function_set = symbols(functions);
+        for f in &function_set {
+            variable_set.remove(f);
+            proto_set.remove(f);
+        }
+        this.variable_set = variable_set;
+        this.proto_set = proto_set;
+        this.function_set = function_set;
+
+        // a macro with the name of a type or term only makes the name refer to itself
+        let macros: BTreeMap<_, _> = macros
+            .into_iter()
+            .filter_map(|(name, m)| Some((name, m?)))
+            .filter(|(name, _)| {
+                !this.typedef_set.contains(name)
+                    && !this.variable_set.contains(name)
+                    && !this.proto_set.contains(name)
+                    && !this.function_set.contains(name)
+            })
+            .collect();
+        let mut macro_uses = BTreeMap::new();
+        for (path, name, ids) in identifier_uses {
+            let mut used = BTreeSet::new();
+            let mut stack: Vec<_> = ids.iter().map(|id| id.as_str()).collect();
+            while let Some(id) = stack.pop() {
+                let m = some_or!(macros.get(id), continue);
+                if used.insert(id.to_string()) {
+                    stack.extend(identifiers(&m.body));
+                }
+            }
+            if !used.is_empty() {
+                macro_uses.insert(this.symbol(&path, &name).to_string(), used);
+            }
+        }
+        let used: BTreeSet<_> = macro_uses.values().flatten().collect();
+        this.macros = macros
+            .into_iter()
+            .filter(|(name, _)| used.contains(name))
+            .collect();
+        this.macro_uses = macro_uses;
+        for (path, name, mut constants) in constant_uses {
+            constants.retain(|(_, m)| this.macros.contains_key(m));
+            if !constants.is_empty() {
+                let symbol = this.symbol(&path, &name).to_string();
+                this.constant_uses.insert(symbol, constants);
+            }
         }
+        this
+    }
+
+    /// `name`, or `name@path` for a static that another file defines as well.
+    pub fn symbol<'a>(&'a self, path: &str, name: &'a str) -> &'a str {
+        self.qualified
+            .get(path)
+            .and_then(|names| names.get(name))
+            .map(|symbol| symbol.as_str())
+            .unwrap_or(name)
+    }
+
+    pub fn comment(&self, path: &str, span: Span) -> Option<&str> {
+        let comment = self.comments.get(path)?.get(&span.start)?;
+        Some(comment.as_str())
     }
 
     pub fn lines(&self, path: &str, span: Span) -> usize {
@@ -335,6 +640,7 @@ impl Program {
                             is_struct_alias,
                             dependencies,
                             path,
+                            comment: self.comment(path, decl.span),
                         };
                         typedefs.insert(name, typedef);
                     }
@@ -452,6 +758,7 @@ impl Program {
                                     struct_type: s,
                                     dependencies,
                                     path,
+                                    comment: self.comment(path, decl.span),
                                 };
                                 structs.insert(name, s);
                             }
@@ -496,6 +803,7 @@ impl Program {
                                     name,
                                     enum_type: e,
                                     path,
+                                    comment: self.comment(path, decl.span),
                                 };
                                 enums.insert(name, e);
                             }
@@ -526,7 +834,7 @@ impl Program {
                     let cnst = is_const(&decl.node);
                     for declarator in &decl.node.declarators {
                         let d = &declarator.node.declarator;
-                        let name = declarator_name(&d.node);
+                        let name = self.symbol(path, declarator_name(&d.node));
                         let identifier = get_identifier(&d.node).unwrap();
                         let types = type_specifiers(&decl.node);
                         let mut visitor = TypeSpecifierVisitor::default();
@@ -536,14 +844,14 @@ impl Program {
                         visitor.visit_init_declarator(&declarator.node, &declarator.span);
                         let mut type_dependencies = visitor.0;
                         self.refine_type_dependencies(&mut type_dependencies);
-                        let mut dependencies = if let Some(i) = &declarator.node.initializer {
+                        let dependencies = if let Some(i) = &declarator.node.initializer {
                             let mut visitor = IdentifierVisitor::default();
                             visitor.visit_initializer(&i.node, &i.span);
                             visitor.0
                         } else {
                             vec![]
                         };
-                        self.refine_dependencies(&mut dependencies);
+                        let dependencies = self.refine_dependencies(path, dependencies);
                         let variable = Variable {
                             declaration: decl,
                             cnst,
@@ -554,6 +862,7 @@ impl Program {
                             type_dependencies,
                             dependencies,
                             path,
+                            comment: self.comment(path, decl.span),
                         };
                         if is_function_proto(&declarator.node) {
                             if self.proto_set.contains(name) {
@@ -634,7 +943,7 @@ impl Program {
             for decl in &parse.unit.0 {
                 if let ExternalDeclaration::FunctionDefinition(func) = &decl.node {
                     let identifier = get_identifier(&func.node.declarator.node).unwrap();
-                    let name = identifier.node.name.as_str();
+                    let name = self.symbol(path, identifier.node.name.as_str());
                     if !function_set.remove(name) {
                         continue;
                     }
@@ -693,11 +1002,11 @@ impl Program {
                     let mut dependencies = get_identifiers(&func.node);
                     let local: BTreeSet<_> = get_local_variables(&func.node).into_iter().collect();
                     dependencies.retain(|i| !local.contains(i.node.name.as_str()));
-                    self.refine_dependencies(&mut dependencies);
+                    let dependencies = self.refine_dependencies(path, dependencies);
 
-                    let mut callees = get_callees(&func.node);
+                    let callees = get_callees(&func.node);
                     let calls = callees.len();
-                    self.refine_callees(&mut callees);
+                    let callees = self.refine_callees(path, callees);
 
                     let f = Function {
                         identifier,
@@ -709,6 +1018,7 @@ impl Program {
                         callees,
                         calls,
                         path,
+                        comment: self.comment(path, decl.span),
                     };
                     functions.insert(name, f);
                 }
@@ -719,11 +1029,12 @@ impl Program {
         functions
     }
 
-    pub fn function_to_string(
-        &self,
-        function: &Function<'_>,
-        mut vec: Vec<(Span, &str)>,
-    ) -> String {
+    pub fn function_to_string(&self, function: &Function<'_>, vec: Vec<(Span, &str)>) -> String {
+        // insertions go first so that they come before replacements at the same position
+        let mut vec: Vec<_> = goto_annotations(function.definition)
+            .into_iter()
+            .chain(vec.into_iter().map(|(span, s)| (span, s.to_string())))
+            .collect();
         for s in &function.definition.node.specifiers {
             if matches!(
                 s.node,
@@ -732,7 +1043,8 @@ impl Program {
                     | DeclarationSpecifier::Alignment(_)
                     | DeclarationSpecifier::Extension(_)
             ) {
-                vec.push((s.span, ""));
+                vec.retain(|(span, _)| !overlap(*span, s.span));
+                vec.push((s.span, String::new()));
             }
         }
         self.replace(function.definition, function.path, vec)
@@ -798,17 +1110,45 @@ impl Program {
         });
     }
 
-    pub fn refine_dependencies(&self, deps: &mut Vec<&Node<Identifier>>) {
-        deps.retain(|d| self.variable_set.contains(&d.node.name));
+    pub fn refine_dependencies<'a>(
+        &'a self,
+        path: &str,
+        deps: Vec<&'a Node<Identifier>>,
+    ) -> Vec<Reference<'a>> {
+        self.resolve(path, deps, |symbol| self.variable_set.contains(symbol))
     }
 
-    pub fn refine_callees(&self, deps: &mut Vec<&Node<Identifier>>) {
-        deps.retain(|d| {
-            self.proto_set.contains(&d.node.name) || self.function_set.contains(&d.node.name)
-        });
+    pub fn refine_callees<'a>(
+        &'a self,
+        path: &str,
+        deps: Vec<&'a Node<Identifier>>,
+    ) -> Vec<Reference<'a>> {
+        self.resolve(path, deps, |symbol| {
+            self.proto_set.contains(symbol) || self.function_set.contains(symbol)
+        })
+    }
+
+    fn resolve<'a, F: Fn(&str) -> bool>(
+        &'a self,
+        path: &str,
+        deps: Vec<&'a Node<Identifier>>,
+        f: F,
+    ) -> Vec<Reference<'a>> {
+        deps.into_iter()
+            .map(|d| Reference {
+                symbol: self.symbol(path, &d.node.name),
+                span: d.span,
+            })
+            .filter(|r| f(r.symbol))
+            .collect()
     }
 }
 
+/// The C name of a symbol.
+pub fn c_name(symbol: &str) -> &str {
+    symbol.split('@').next().unwrap()
+}
+
 fn declarator_names(decl: &Declaration) -> Vec<&str> {
     decl.declarators
         .iter()
@@ -842,12 +1182,8 @@ fn find_lib_spans(parse: &Parse) -> Vec<Span> {
     let mut lib_spans = vec![];
     let mut pos = 0;
     for line in parse.source.lines() {
-        if line.starts_with('#') && !line.starts_with("#pragma") {
-            let path = line
-                .split(' ')
-                .find_map(|s| s.strip_prefix('"'))
-                .expect(line);
-            if path.starts_with('/') && !path.starts_with("/usr/include/arpa") {
+        if let Some((path, _, system)) = parse_line_marker(line) {
+            if system && !path.starts_with("/usr/include/arpa") {
                 lib_start.get_or_insert(pos);
             } else if let Some(start) = lib_start.take() {
                 lib_spans.push(Span::span(start, pos));
@@ -861,6 +1197,300 @@ fn find_lib_spans(parse: &Parse) -> Vec<Span> {
     lib_spans
 }
 
+// e.g. `# 1 "/usr/include/stdio.h" 1 3 4`, where 3 marks a system header
+fn parse_line_marker(line: &str) -> Option<(&str, usize, bool)> {
+    let (line_no, rest) = line.strip_prefix("# ")?.split_once(' ')?;
+    let line_no = line_no.parse().ok()?;
+    let (path, flags) = rest.strip_prefix('"')?.rsplit_once('"')?;
+    let system = flags.split_whitespace().any(|flag| flag == "3");
+    Some((path, line_no, system))
+}
+
+fn line_origins(source: &str) -> Vec<Option<(&str, usize, bool)>> {
+    let mut origins = vec![];
+    let mut current = None;
+    for line in source.lines() {
+        if let Some(marker) = parse_line_marker(line) {
+            origins.push(None);
+            current = Some(marker);
+        } else {
+            origins.push(current);
+            if let Some((_, line_no, _)) = &mut current {
+                *line_no += 1;
+            }
+        }
+    }
+    origins
+}
+
+// a blank line separates a comment from the code below it
+fn leading_comment(lines: &[String], i: usize) -> Option<String> {
+    let mut comment = vec![];
+    let mut i = i;
+    while let Some(last) = i.checked_sub(1) {
+        let line = lines.get(last)?.trim();
+        if line.starts_with("//") {
+            comment.push(line.trim_start_matches('/').to_string());
+            i = last;
+        } else if line.ends_with("*/") {
+            let first = (0..=last).rev().find(|j| lines[*j].contains("/*"))?;
+            if !lines[first].trim().starts_with("/*") {
+                break;
+            }
+            let block = lines[first..=last].join("\n");
+            let mut block_lines = vec![];
+            for c in block.split("*/") {
+                let c = c.trim();
+                if c.is_empty() {
+                    continue;
+                }
+                let c = some_or!(c.strip_prefix("/*"), return None);
+                for line in c.trim_start_matches('*').lines() {
+                    let line = line.trim();
+                    block_lines.push(line.strip_prefix('*').unwrap_or(line).to_string());
+                }
+            }
+            comment.extend(block_lines.into_iter().rev());
+            i = first;
+        } else {
+            break;
+        }
+    }
+    let lines: Vec<_> = comment
+        .iter()
+        .rev()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line).trim_end())
+        .skip_while(|line| line.is_empty())
+        .collect();
+    let end = lines.iter().rposition(|line| !line.is_empty())?;
+    Some(lines[..=end].join("\n"))
+}
+
+// punctuators are split into single characters
+fn tokens(code: &str) -> Vec<Range<usize>> {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut tokens = vec![];
+    let mut line_start = true;
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c == '\n' {
+            line_start = true;
+            continue;
+        } else if c.is_whitespace() {
+            continue;
+        }
+        let directive = line_start && c == '#';
+        line_start = false;
+        if directive {
+            while chars.next_if(|(_, d)| *d != '\n').is_some() {}
+        } else if c == '"' || c == '\'' {
+            let mut escaped = false;
+            let mut end = code.len();
+            for (i, d) in chars.by_ref() {
+                if escaped {
+                    escaped = false;
+                } else if d == '\\' {
+                    escaped = true;
+                } else if d == c || d == '\n' {
+                    end = i + 1;
+                    line_start = d == '\n';
+                    break;
+                }
+            }
+            tokens.push(start..end);
+        } else if c == '/' && chars.next_if(|(_, d)| *d == '/').is_some() {
+            while chars.next_if(|(_, d)| *d != '\n').is_some() {}
+        } else if c == '/' && chars.next_if(|(_, d)| *d == '*').is_some() {
+            let mut star = false;
+            for (_, d) in chars.by_ref() {
+                if star && d == '/' {
+                    break;
+                }
+                star = d == '*';
+            }
+        } else if is_ident(c) {
+            // numbers include their suffixes and fractions
+            let number = c.is_ascii_digit();
+            let mut end = start + 1;
+            while let Some((i, d)) = chars.next_if(|(_, d)| is_ident(*d) || (number && *d == '.')) {
+                end = i + d.len_utf8();
+            }
+            tokens.push(start..end);
+        } else {
+            tokens.push(start..start + c.len_utf8());
+        }
+    }
+    tokens
+}
+
+fn identifiers(code: &str) -> BTreeSet<&str> {
+    tokens_of(code)
+        .into_iter()
+        .filter(|token| token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'))
+        .collect()
+}
+
+fn expand<'a>(
+    tokens: &[&'a str],
+    macros: &'a BTreeMap<String, Macro>,
+    active: &mut Vec<&'a str>,
+) -> Option<Vec<&'a str>> {
+    let mut expanded = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = tokens[i];
+        i += 1;
+        let m = some_or!(macros.get(token), {
+            expanded.push(token);
+            continue;
+        });
+        if active.contains(&token) {
+            expanded.push(token);
+            continue;
+        }
+        let body: Vec<_> = tokens_of(&m.body);
+        if body.iter().any(|t| *t == "#") {
+            return None;
+        }
+        let body = if let Some(params) = &m.params {
+            if tokens.get(i) != Some(&"(") {
+                expanded.push(token);
+                continue;
+            }
+            if params.iter().any(|p| p == "...") {
+                return None;
+            }
+            let mut args = vec![vec![]];
+            let mut depth = 0;
+            loop {
+                i += 1;
+                match *tokens.get(i)? {
+                    ")" if depth == 0 => break,
+                    "," if depth == 0 => args.push(vec![]),
+                    t => {
+                        if t == "(" {
+                            depth += 1;
+                        } else if t == ")" {
+                            depth -= 1;
+                        }
+                        args.last_mut().unwrap().push(t);
+                    }
+                }
+            }
+            i += 1;
+            if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+                args.clear();
+            }
+            if args.len() != params.len() {
+                return None;
+            }
+            let args = args
+                .iter()
+                .map(|arg| expand(arg, macros, active))
+                .collect::<Option<Vec<_>>>()?;
+            let mut substituted = vec![];
+            for t in body {
+                match params.iter().position(|p| p == t) {
+                    Some(k) => substituted.extend(&args[k]),
+                    None => substituted.push(t),
+                }
+            }
+            substituted
+        } else {
+            body
+        };
+        active.push(token);
+        expanded.extend(expand(&body, macros, active)?);
+        active.pop();
+    }
+    Some(expanded)
+}
+
+fn tokens_of(code: &str) -> Vec<&str> {
+    tokens(code).into_iter().map(|range| &code[range]).collect()
+}
+
+// compares the lines token by token up to the first difference that the macros do not explain
+fn expanded_constants(
+    original: &str,
+    preprocessed: &str,
+    macros: &BTreeMap<String, Macro>,
+) -> Vec<(Range<usize>, String)> {
+    let original_tokens = tokens_of(original);
+    let preprocessed_ranges = tokens(preprocessed);
+    let preprocessed_tokens: Vec<_> = preprocessed_ranges
+        .iter()
+        .map(|range| &preprocessed[range.clone()])
+        .collect();
+    let mut constants = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < original_tokens.len() {
+        let token = original_tokens[i];
+        if let Some(m) = macros.get(token) {
+            // the invocation of a function-like macro ends at the parenthesis closing its
+            // arguments
+            let mut end = i + 1;
+            if m.params.is_some() && original_tokens.get(end) == Some(&"(") {
+                let mut depth = 0;
+                while let Some(t) = original_tokens.get(end) {
+                    end += 1;
+                    if *t == "(" {
+                        depth += 1;
+                    } else if *t == ")" {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+            }
+            if let Some(expanded) = expand(&original_tokens[i..end], macros, &mut vec![]) {
+                let k = j + expanded.len();
+                if preprocessed_tokens.get(j..k) == Some(&expanded[..]) {
+                    if m.params.is_none() && k > j {
+                        let range = preprocessed_ranges[j].start..preprocessed_ranges[k - 1].end;
+                        constants.push((range, token.to_string()));
+                    }
+                    i = end;
+                    j = k;
+                    continue;
+                }
+            }
+        }
+        if preprocessed_tokens.get(j) != Some(&token) {
+            break;
+        }
+        i += 1;
+        j += 1;
+    }
+    constants
+}
+
+// the codes have the same tokens; spans that do not start and end at tokens are dropped
+fn corresponding_spans<S>(
+    (from, from_span): (&str, Span),
+    (to, to_span): (&str, Span),
+    spans: Vec<(Span, S)>,
+) -> Vec<(Span, S)> {
+    let from_tokens = tokens(&from[from_span.start..from_span.end]);
+    let to_tokens = tokens(&to[to_span.start..to_span.end]);
+    if from_tokens.len() != to_tokens.len() {
+        return vec![];
+    }
+    spans
+        .into_iter()
+        .filter_map(|(span, s)| {
+            let start = span.start - from_span.start;
+            let end = span.end - from_span.start;
+            let i = from_tokens.iter().position(|r| r.start == start)?;
+            let k = from_tokens.iter().position(|r| r.end == end)?;
+            let start = to_span.start + to_tokens[i].start;
+            let end = to_span.start + to_tokens[k].end;
+            Some((Span::span(start, end), s))
+        })
+        .collect()
+}
+
 pub fn overlap(s1: Span, s2: Span) -> bool {
     s1.start < s2.end && s2.start < s1.end
 }
@@ -879,6 +1509,13 @@ fn is_extern(decl: &Declaration) -> bool {
     })
 }
 
+fn is_static(specifiers: &[Node<DeclarationSpecifier>]) -> bool {
+    specifiers.iter().any(|s| match &s.node {
+        DeclarationSpecifier::StorageClass(s) => matches!(s.node, StorageClassSpecifier::Static),
+        _ => false,
+    })
+}
+
 fn is_const(decl: &Declaration) -> bool {
     decl.specifiers.iter().any(|s| match &s.node {
         DeclarationSpecifier::TypeQualifier(t) => matches!(t.node, TypeQualifier::Const),
@@ -990,6 +1627,97 @@ impl<'ast> Visit<'ast> for NameVisitor<'ast> {
     }
 }
 
+#[derive(Default)]
+struct GotoVisitor<'ast> {
+    // whether each label follows a `return`
+    labels: Vec<(&'ast str, Span, bool)>,
+    gotos: Vec<(&'ast str, Span)>,
+    after_return: Vec<Span>,
+}
+
+impl<'ast> Visit<'ast> for GotoVisitor<'ast> {
+    fn visit_statement(&mut self, statement: &'ast Statement, span: &'ast Span) {
+        match statement {
+            Statement::Labeled(l) => {
+                if let Label::Identifier(id) = &l.node.label.node {
+                    let after_return = self.after_return.contains(span);
+                    self.labels.push((&id.node.name, *span, after_return));
+                }
+            }
+            Statement::Goto(id) => self.gotos.push((&id.node.name, *span)),
+            Statement::Compound(items) => {
+                for items in items.windows(2) {
+                    if let (BlockItem::Statement(prev), BlockItem::Statement(next)) =
+                        (&items[0].node, &items[1].node)
+                    {
+                        if matches!(prev.node, Statement::Return(_)) {
+                            self.after_return.push(next.span);
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+        visit::visit_statement(self, statement, span);
+    }
+}
+
+/// Comments that map the `goto`s of a function onto labelled blocks and loops.
+pub fn goto_annotations(node: &Node<FunctionDefinition>) -> Vec<(Span, String)> {
+    let mut visitor = GotoVisitor::default();
+    visitor.visit_function_definition(&node.node, &node.span);
+    let mut annotations = vec![];
+    for (label, label_span, after_return) in visitor.labels {
+        let gotos: Vec<_> = visitor
+            .gotos
+            .iter()
+            .filter(|(name, _)| *name == label)
+            .map(|(_, span)| *span)
+            .collect();
+        let forward = gotos.iter().all(|span| span.end <= label_span.start);
+        let backward = gotos.iter().all(|span| span.start >= label_span.end);
+        let (comment, jump) = if gotos.is_empty() || forward == backward {
+            continue;
+        } else if forward && after_return {
+            (
+                format!(
+                    "/* error exit: only the gotos to `{0}` reach the code from here on; translate \
+                     the code above as a block labelled `'{0}` that they leave, followed by this \
+                     code */ ",
+                    label
+                ),
+                "break",
+            )
+        } else if forward {
+            (
+                format!(
+                    "/* cleanup: the code from here on runs after the code above and after the \
+                     gotos to `{0}`; translate the code above as a block labelled `'{0}` that they \
+                     leave, followed by this code */ ",
+                    label
+                ),
+                "break",
+            )
+        } else {
+            (
+                format!(
+                    "/* retry: the gotos to `{0}` jump back to here; translate the code from here \
+                     to the last of them as a loop labelled `'{0}` that they continue */ ",
+                    label
+                ),
+                "continue",
+            )
+        };
+        let start = Span::span(label_span.start, label_span.start);
+        annotations.push((start, comment));
+        for span in gotos {
+            let end = Span::span(span.end, span.end);
+            annotations.push((end, format!(" /* {} '{} */", jump, label)));
+        }
+    }
+    annotations
+}
+
 pub fn find_names(node: &Node<FunctionDefinition>, name: &str) -> Vec<Span> {
     let mut visitor = NameVisitor::default();
     visitor.visit_function_definition(&node.node, &node.span);
@@ -1107,7 +1835,11 @@ fn type_of(
             }
         }
         TypeSpecifier::TypeOf(_) => todo!("{:?}", ty),
-        TypeSpecifier::TS18661Float(_) => todo!("{:?}", ty),
+        // Rust has no floating point type wider than `f64`
+        TypeSpecifier::TS18661Float(t) if t.width > 64 => {
+            Type::from_name(format!("_Float{}", t.width))
+        }
+        TypeSpecifier::TS18661Float(_) => Type::from_name("float".to_string()),
     };
     while let Some(decl) = &declarator {
         for d in &decl.node.derived {
@@ -1129,50 +1861,82 @@ fn type_of(
     ty
 }
 
+/// An entry of `compile_commands.json`.
 #[derive(Serialize, Deserialize)]
 struct CompileCommand {
+    #[serde(default)]
     arguments: Vec<String>,
+    #[serde(default)]
+    command: Option<String>,
     directory: PathBuf,
     file: PathBuf,
 }
 
 impl CompileCommand {
+    fn arguments(&self) -> Vec<String> {
+        let arguments = match &self.command {
+            Some(command) if self.arguments.is_empty() => shell_split(command),
+            _ => self.arguments.clone(),
+        };
+        expand_response_files(arguments, &self.directory, 0)
+    }
+
     fn preprocess(&self) -> Option<Preprocessed> {
-        if self.arguments[0] != "cc" {
+        let mut path = self.directory.clone();
+        path.push(&self.file);
+        if path.extension().and_then(|e| e.to_str()) != Some("c") {
+            tracing::warn!("skipping non-C file {:?}", path);
             return None;
         }
 
-        let mut arguments = self.arguments.clone();
-        arguments.retain(|x| !x.starts_with("-g") && !x.starts_with("-O"));
-
-        let mut command = Command::new("gcc");
-        command.current_dir(&self.directory).arg("-E");
-        if let Some(i) = arguments.iter().position(|x| x == "-o") {
-            command.args(&arguments[1..i]);
-            command.args(&arguments[i + 2..]);
-        } else {
-            command.args(&arguments[1..]);
+        let arguments = self.arguments();
+        let (driver, arguments) = some_or!(arguments.split_first(), {
+            tracing::warn!("skipping empty command for {:?}", path);
+            return None;
+        });
+        let mut args = vec![];
+        let mut iter = arguments.iter();
+        while let Some(arg) = iter.next() {
+            if matches!(arg.as_str(), "-o" | "-MF" | "-MT" | "-MQ") {
+                iter.next();
+            } else if !(arg.starts_with("-g")
+                || arg.starts_with("-O")
+                || (arg.starts_with("-o") && arg.len() > 2)
+                || matches!(arg.as_str(), "-c" | "-MD" | "-MMD" | "-MP"))
+            {
+                args.push(arg.as_str());
+            }
         }
-        let output = command.output().unwrap();
-        assert!(output.status.success(), "{:?}", command);
-        let long_code = String::from_utf8(output.stdout)
-            .unwrap()
-            .replace("typedef __int128", "typedef int")
-            .replace("typedef unsigned __int128", "typedef int");
-
-        command.arg("-P");
-        let output = command.output().unwrap();
-        assert!(output.status.success());
-        let code = String::from_utf8(output.stdout)
-            .unwrap()
-            .replace("typedef __int128", "typedef int")
-            .replace("typedef unsigned __int128", "typedef int");
 
-        let mut path = self.directory.clone();
-        path.push(&self.file);
+        let run = |driver: &str, extra: &[&str]| {
+            let mut command = Command::new(driver);
+            command
+                .current_dir(&self.directory)
+                .arg("-E")
+                .args(&args)
+                .args(extra);
+            let output = command.output().ok()?;
+            assert!(output.status.success(), "{:?}", command);
+            let code = String::from_utf8(output.stdout).unwrap();
+            Some(format!(
+                "{}{}",
+                EXTENDED_TYPES,
+                normalize_extended_types(&code)
+            ))
+        };
+        // `-dD` keeps the macro definitions, which lang-c skips like line markers
+        let (driver, long_code) = match run(driver, &["-dD"]) {
+            Some(long_code) => (driver.as_str(), long_code),
+            None => {
+                tracing::warn!("cannot run {}; preprocessing {:?} with gcc", driver, path);
+                ("gcc", run("gcc", &["-dD"]).unwrap())
+            }
+        };
+        let code = run(driver, &["-P"]).unwrap();
 
         let preprocessed = Preprocessed {
             path,
+            directory: self.directory.clone(),
             code,
             long_code,
         };
@@ -1180,8 +1944,136 @@ impl CompileCommand {
     }
 }
 
+// a system header, so that these declarations only matter for parsing
+const EXTENDED_TYPES: &str = "# 1 \"<simcrat>\" 3\n\
+    typedef long __int128_t;\n\
+    typedef unsigned long __uint128_t;\n";
+
+/// The preprocessed code without `EXTENDED_TYPES`, for C compilers.
+pub fn compilable_source(source: &str) -> &str {
+    source.strip_prefix(EXTENDED_TYPES).unwrap_or(source)
+}
+
+fn normalize_extended_types(code: &str) -> String {
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut normalized = String::with_capacity(code.len());
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c == '"' || c == '\'' {
+            normalized.push(c);
+            let mut escaped = false;
+            for (_, d) in chars.by_ref() {
+                normalized.push(d);
+                if escaped {
+                    escaped = false;
+                } else if d == '\\' {
+                    escaped = true;
+                } else if d == c {
+                    break;
+                }
+            }
+        } else if is_ident(c) {
+            let mut end = start + 1;
+            while let Some((i, d)) = chars.next_if(|(_, d)| is_ident(*d)) {
+                end = i + d.len_utf8();
+            }
+            let word = &code[start..end];
+            match word {
+                "__int128" => {
+                    // a preceding `signed` or `unsigned` is folded into the name
+                    let prev = normalized.trim_end();
+                    let prev_start = prev.rfind(|c| !is_ident(c)).map_or(0, |i| i + 1);
+                    let ty = match &prev[prev_start..] {
+                        "unsigned" => Some("__uint128_t"),
+                        "signed" | "__signed__" => Some("__int128_t"),
+                        _ => None,
+                    };
+                    if let Some(ty) = ty {
+                        normalized.truncate(prev_start);
+                        normalized.push_str(ty);
+                    } else {
+                        normalized.push_str("__int128_t");
+                    }
+                }
+                "__float128" => normalized.push_str("_Float128"),
+                _ => normalized.push_str(word),
+            }
+        } else {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+/// Splits a command line like a POSIX shell, without expansions.
+fn shell_split(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                words.extend(word.take());
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                for c in chars.by_ref() {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => (),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                let word = word.get_or_insert_with(String::new);
+                match chars.next() {
+                    Some('\n') | None => (),
+                    Some(c) => word.push(c),
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Replaces every `@file` argument with the arguments in the file, as gcc and clang do.
+fn expand_response_files(arguments: Vec<String>, directory: &Path, depth: usize) -> Vec<String> {
+    arguments
+        .into_iter()
+        .flat_map(|arg| {
+            let file = some_or!(arg.strip_prefix('@'), return vec![arg]);
+            let content = some_or!(fs::read_to_string(directory.join(file)).ok(), {
+                return vec![arg];
+            });
+            assert!(depth < 16, "recursive response file: {}", file);
+            expand_response_files(shell_split(&content), directory, depth + 1)
+        })
+        .collect()
+}
+
 struct Preprocessed {
     path: PathBuf,
+    directory: PathBuf,
     code: String,
     long_code: String,
 }
@@ -1189,10 +2081,12 @@ struct Preprocessed {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempPath;
 
     fn parse(code: &str) -> Program {
         let preprocessed = Preprocessed {
             path: PathBuf::from("test.c"),
+            directory: PathBuf::new(),
             code: code.to_string(),
             long_code: code.to_string(),
         };
@@ -1326,4 +2220,217 @@ mod tests {
         assert_eq!(params[1], ptr(&int));
         assert_eq!(ret, int);
     }
+
+    #[test]
+    fn test_statics() {
+        let file = |path: &str, code: &str| Preprocessed {
+            path: PathBuf::from(path),
+            directory: PathBuf::new(),
+            code: code.to_string(),
+            long_code: code.to_string(),
+        };
+        let program = Program::new(vec![
+            file(
+                "a.c",
+                "static int n; static int g(void) { return n; } int f(void) { return g(); }",
+            ),
+            file(
+                "b.c",
+                "static int n; static int g(void) { return n; } int h(void) { return g(); }",
+            ),
+            file("c.c", "static int m; int k(void) { return m; }"),
+        ]);
+        let functions = program.functions();
+        let names: Vec<_> = functions.keys().copied().collect();
+        assert_eq!(names, ["f", "g@a.c", "g@b.c", "h", "k"]);
+        assert_eq!(functions["h"].callees[0].symbol, "g@b.c");
+        assert_eq!(functions["g@a.c"].dependencies[0].symbol, "n@a.c");
+        assert_eq!(functions["k"].dependencies[0].symbol, "m");
+        let (variables, _) = program.variables();
+        let names: Vec<_> = variables.keys().copied().collect();
+        assert_eq!(names, ["m", "n@a.c", "n@b.c"]);
+        assert_eq!(c_name("n@a.c"), "n");
+    }
+
+    #[test]
+    fn test_goto_annotations() {
+        let code = "int f(int x) { if (x) goto out; x++; out: return x; }\n\
+                    int g(int x) { if (x) goto fail; return 0; fail: return 1; }\n\
+                    int h(int x) { again: x--; if (x) goto again; return x; }\n";
+        let program = Program::new(vec![Preprocessed {
+            path: PathBuf::from("a.c"),
+            directory: PathBuf::new(),
+            code: code.to_string(),
+            long_code: code.to_string(),
+        }]);
+        let functions = program.functions();
+        let f = program.function_to_string(&functions["f"], vec![]);
+        assert!(f.contains("goto out; /* break 'out */"), "{}", f);
+        assert!(f.contains("/* cleanup: "), "{}", f);
+        assert!(f.contains("block labelled `'out` that they leave, followed by this code */ out:"));
+        let g = program.function_to_string(&functions["g"], vec![]);
+        assert!(g.contains("goto fail; /* break 'fail */"), "{}", g);
+        assert!(g.contains("/* error exit: "), "{}", g);
+        let h = program.function_to_string(&functions["h"], vec![]);
+        assert!(h.contains("goto again; /* continue 'again */"), "{}", h);
+        assert!(h.contains("/* retry: "), "{}", h);
+        assert!(h.contains("loop labelled `'again` that they continue */ again: x--;"));
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            shell_split(r#"cc -DX="a b" -I'inc dir' a\ b.c "q\"x" -c"#),
+            ["cc", "-DX=a b", "-Iinc dir", "a b.c", "q\"x", "-c"]
+        );
+        assert_eq!(shell_split("  gcc\t-c  ''  "), ["gcc", "-c", ""]);
+
+        let dir = TempPath::new("rsp");
+        fs::create_dir_all(dir.path()).unwrap();
+        fs::write(dir.path().join("a.rsp"), "-DA @b.rsp").unwrap();
+        fs::write(dir.path().join("b.rsp"), "-I'x y'").unwrap();
+        let command = CompileCommand {
+            arguments: vec![],
+            command: Some("/usr/bin/clang @a.rsp -c m.c".to_string()),
+            directory: dir.path().to_path_buf(),
+            file: PathBuf::from("m.c"),
+        };
+        assert_eq!(
+            command.arguments(),
+            ["/usr/bin/clang", "-DA", "-Ix y", "-c", "m.c"]
+        );
+    }
+
+    #[test]
+    fn test_extended_types() {
+        assert_eq!(
+            normalize_extended_types(
+                "unsigned __int128 a; signed  __int128 b; const __int128 c; __float128 d; \
+                 char *s = \"__int128\"; int __int128x;"
+            ),
+            "__uint128_t a; __int128_t b; const __int128_t c; _Float128 d; \
+             char *s = \"__int128\"; int __int128x;"
+        );
+
+        let code = format!(
+            "{}# 1 \"test.c\"\n{}",
+            EXTENDED_TYPES,
+            normalize_extended_types(
+                "__extension__ typedef unsigned long long ull;\n\
+                 extern int g(int) __asm__(\"real_g\");\n\
+                 __attribute__((noinline)) static unsigned __int128 \
+                 f(__int128 a, _Float128 b, ull c) { return a; }"
+            )
+        );
+        let program = parse(&code);
+        assert_eq!(program.typedef_set.iter().collect::<Vec<_>>(), ["ull"]);
+        let func = &program.functions()["f"];
+        let FunTySig { params, ret, .. } = &func.type_signature;
+        let int = Type::from_name("int".to_string());
+        assert_eq!(params[0], int);
+        assert_eq!(params[1], Type::from_name("_Float128".to_string()));
+        assert_eq!(ret, &int);
+        let source = &program.parses.values().next().unwrap().source;
+        assert!(compilable_source(source).starts_with("# 1 \"test.c\""));
+    }
+
+    #[test]
+    fn test_macros() {
+        let dir = TempPath::new("macros");
+        fs::create_dir_all(dir.path()).unwrap();
+        let source = "#define N 4\n\
+                      #define M (N * 2) /* M */\n\
+                      #define SQ(x) ((x) * (x))\n\
+                      #define E\n\
+                      int a[M];\n\
+                      int f(int x) {\n  return SQ(x) + N; // N\n}\n\
+                      int g(void) { return 1; }\n";
+        fs::write(dir.path().join("m.c"), source).unwrap();
+        let code = "int a[(4 * 2)];\n\
+                    int f(int x) {\n  return ((x) * (x)) + 4;\n}\n\
+                    int g(void) { return 1; }\n";
+        let long_code = "# 1 \"/usr/include/x.h\" 1 3 4\n\
+                         #define SYS 1\n\
+                         # 1 \"m.c\"\n\
+                         #define N 4\n\
+                         #define M (N * 2)\n\
+                         #define SQ(x) ((x) * (x))\n\
+                         #define E \n\
+                         int a[(4 * 2)];\n\
+                         int f(int x) {\n  return ((x) * (x)) + 4;\n}\n\
+                         int g(void) { return 1; }\n";
+        let program = Program::new(vec![Preprocessed {
+            path: PathBuf::from("m.c"),
+            directory: dir.path().to_path_buf(),
+            code: code.to_string(),
+            long_code: long_code.to_string(),
+        }]);
+
+        let names: Vec<_> = program.macros.keys().collect();
+        assert_eq!(names, ["M", "N", "SQ"]);
+        assert_eq!(program.macros["SQ"].params.as_ref().unwrap(), &["x"]);
+        let uses = |name: &str| -> Vec<_> { program.macro_uses[name].iter().collect() };
+        assert_eq!(uses("a"), ["M", "N"]);
+        assert_eq!(uses("f"), ["N", "SQ"]);
+        assert!(!program.macro_uses.contains_key("g"));
+
+        let constants = |name: &str| -> Vec<_> {
+            let source = &program.parses["m.c"].source;
+            program.constant_uses[name]
+                .iter()
+                .map(|(span, m)| (&source[span.start..span.end], m.as_str()))
+                .collect()
+        };
+        assert_eq!(constants("a"), [("(4 * 2)", "M")]);
+        assert_eq!(constants("f"), [("4", "N")]);
+
+        let names = BTreeMap::from([("M", "DOUBLE"), ("N", "SIZE")]);
+        assert_eq!(
+            program.macros["M"].to_string(&names),
+            "#define DOUBLE (SIZE * 2)"
+        );
+        assert_eq!(
+            identifiers("x = 1.5e3f + 'a' + \"b c\" /* d */ + y.z; // w"),
+            ["x", "y", "z"].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let dir = TempPath::new("comments");
+        fs::create_dir_all(dir.path()).unwrap();
+        let source = "/* license */\n\
+                      \n\
+                      /**\n * A pair.\n *\n * Of ints.\n */\n\
+                      struct s { int a; int b; };\n\
+                      // The count.\n//   Indented.\n\
+                      int n;\n\
+                      int m;\n\
+                      /* Adds. */ /* Twice. */\n\
+                      int f(void) { return 1; }\n";
+        fs::write(dir.path().join("c.c"), source).unwrap();
+        let code = "struct s { int a; int b; };\n\
+                    int n;\n\
+                    int m;\n\
+                    int f(void) { return 1; }\n";
+        let long_code = format!("# 1 \"c.c\"\n{}", "\n".repeat(7))
+            + "struct s { int a; int b; };\n\n\n\
+               int n;\n\
+               int m;\n\n\
+               int f(void) { return 1; }\n";
+        let program = Program::new(vec![Preprocessed {
+            path: PathBuf::from("c.c"),
+            directory: dir.path().to_path_buf(),
+            code: code.to_string(),
+            long_code,
+        }]);
+
+        let structs = program.structs();
+        assert_eq!(structs["s"].comment, Some("A pair.\n\nOf ints."));
+        let (variables, _) = program.variables();
+        assert_eq!(variables["n"].comment, Some("The count.\n  Indented."));
+        assert_eq!(variables["m"].comment, None);
+        let functions = program.functions();
+        assert_eq!(functions["f"].comment, Some("Adds.\nTwice."));
+    }
 }
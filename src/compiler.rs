@@ -15,26 +15,32 @@ use rustc_errors::{
 };
 use rustc_hash::{FxHashMap, FxHashSet};
 use rustc_hir::{
-    def::Res,
-    def_id::{DefId, LocalDefId},
+    def::{DefKind, Res},
+    def_id::{DefId, LocalDefId, CRATE_DEF_ID},
     hir_id::HirId,
     intravisit::{self, Visitor},
-    Expr, ExprKind, FnDecl, FnRetTy, GenericArg, GenericBound, GenericParam, GenericParamKind,
-    Item, ItemKind, MutTy, Mutability, Node, Path, PathSegment, PrimTy, QPath, TraitRef, Ty,
-    TyKind,
+    Block, BlockCheckMode, Expr, ExprKind, FnDecl, FnRetTy, GenericArg, GenericBound, GenericParam,
+    GenericParamKind, Item, ItemKind, MutTy, Mutability, Node, Path, PathSegment, PrimTy, QPath,
+    TraitRef, Ty, TyKind, UnOp, UnsafeSource, Unsafety,
 };
 use rustc_interface::{interface::Compiler, Config};
-use rustc_middle::{dep_graph::DepContext, hir::nested_filter, ty::TyCtxt};
+use rustc_middle::{
+    dep_graph::DepContext,
+    hir::nested_filter,
+    ty::{TyCtxt, TypeckResults},
+};
 use rustc_session::{
     config::{CheckCfg, Input, Options},
     parse::ParseSess,
 };
 use rustc_span::{
+    edit_distance::edit_distance_with_substrings,
     hygiene::{ExpnKind, MacroKind},
     source_map::{FileName, SourceMap},
     BytePos, Pos, Span, SpanData,
 };
 use rustfix::{LinePosition, LineRange, Replacement, Snippet, Solution, Suggestion};
+use serde::{Deserialize, Serialize};
 
 use crate::c_parser::TypeSort;
 
@@ -147,6 +153,8 @@ impl fmt::Display for WithSourceMap<'_, &SubDiagnostic> {
 
 #[derive(Debug, Clone)]
 struct Diagnostic {
+    level: &'static str,
+    stage: Stage,
     code: Option<String>,
     message: Vec<String>,
     span: MultiSpan,
@@ -156,7 +164,7 @@ struct Diagnostic {
 
 impl fmt::Display for WithSourceMap<'_, &Diagnostic> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "error")?;
+        write!(f, "{}", self.inner.level)?;
         if let Some(code) = &self.inner.code {
             write!(f, "[{}]", code)?;
         }
@@ -179,7 +187,7 @@ impl fmt::Display for WithSourceMap<'_, &Diagnostic> {
 fn code_to_string(code: DiagnosticId) -> Option<String> {
     match code {
         DiagnosticId::Error(s) => Some(s),
-        _ => None,
+        DiagnosticId::Lint { name, .. } => Some(name),
     }
 }
 
@@ -197,7 +205,8 @@ impl<'a, T> WithSourceMap<'a, T> {
 #[derive(Default)]
 struct EmitterInner {
     diagnostics: Vec<Diagnostic>,
-    warning_counter: usize,
+    lints: Vec<Diagnostic>,
+    stage: Stage,
 }
 
 struct CollectingEmitter {
@@ -316,7 +325,7 @@ impl CollectingEmitter {
         }
     }
 
-    fn diagnostic(&self, diag: &rustc_errors::Diagnostic) -> Diagnostic {
+    fn diagnostic(&self, diag: &rustc_errors::Diagnostic, stage: Stage) -> Diagnostic {
         let message = diag
             .message
             .iter()
@@ -338,6 +347,8 @@ impl CollectingEmitter {
             .map(|s| self.code_suggestion(s, diag))
             .collect();
         Diagnostic {
+            level: diag.level().to_str(),
+            stage,
             message,
             code,
             span,
@@ -359,13 +370,28 @@ impl Translate for CollectingEmitter {
 
 impl Emitter for CollectingEmitter {
     fn emit_diagnostic(&mut self, diag: &rustc_errors::Diagnostic) {
+        let mut inner = self.inner.lock().unwrap();
         match diag.level() {
             Level::Error { .. } => {
-                let diag = self.diagnostic(diag);
+                // name resolution errors may be emitted as late as type checking
+                let stage = match &diag.code {
+                    Some(DiagnosticId::Error(code)) if RESOLVE_ERRORS.contains(&code.as_str()) => {
+                        Stage::Resolve
+                    }
+                    _ => inner.stage,
+                };
+                let diag = self.diagnostic(diag, stage);
                 tracing::info!("{:?}", diag);
-                self.inner.lock().unwrap().diagnostics.push(diag);
+                inner.diagnostics.push(diag);
+            }
+            // the lints of the unsafety check are emitted again by the lint pass
+            Level::Warning(_)
+                if matches!(diag.code, Some(DiagnosticId::Lint { .. }))
+                    && inner.stage != Stage::Unsafety =>
+            {
+                let diag = self.diagnostic(diag, inner.stage);
+                inner.lints.push(diag);
             }
-            Level::Warning(_) => self.inner.lock().unwrap().warning_counter += 1,
             _ => (),
         }
     }
@@ -375,6 +401,14 @@ impl Emitter for CollectingEmitter {
     }
 }
 
+/// The codes of the errors of name resolution.
+static RESOLVE_ERRORS: [&str; 31] = [
+    "E0404", "E0405", "E0407", "E0408", "E0411", "E0412", "E0415", "E0416", "E0422", "E0423",
+    "E0424", "E0425", "E0426", "E0428", "E0429", "E0430", "E0431", "E0432", "E0433", "E0434",
+    "E0435", "E0437", "E0438", "E0530", "E0531", "E0532", "E0573", "E0574", "E0575", "E0576",
+    "E0659",
+];
+
 struct SilentEmitter;
 
 impl Translate for SilentEmitter {
@@ -430,12 +464,17 @@ fn make_config(code: &str) -> Config {
 }
 
 fn find_deps() -> Options {
+    let args: Vec<_> = std::iter::once("a.rs".to_string())
+        .chain(extern_args())
+        .collect();
+    let matches = rustc_driver::handle_options(&args).unwrap();
+    rustc_session::config::build_session_options(&matches)
+}
+
+/// The rustc arguments that make the crates of `deps_crate` available.
+pub fn extern_args() -> Vec<String> {
     let dep = "deps_crate/target/debug/deps";
-    let mut args: Vec<_> = vec![
-        "a.rs".to_string(),
-        "-L".to_string(),
-        format!("dependency={}", dep),
-    ];
+    let mut args: Vec<_> = vec!["-L".to_string(), format!("dependency={}", dep)];
     let files: BTreeMap<_, _> = std::fs::read_dir(dep)
         .unwrap()
         .filter_map(|f| {
@@ -453,11 +492,10 @@ fn find_deps() -> Options {
         args.push("--extern".to_string());
         args.push(d);
     }
-    let matches = rustc_driver::handle_options(&args).unwrap();
-    rustc_session::config::build_session_options(&matches)
+    args
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct FunTySig {
     pub params: Vec<Type>,
     pub ret: Type,
@@ -497,7 +535,7 @@ impl FunTySig {
         }
     }
 
-    fn contains(&self, s: &str) -> bool {
+    pub fn contains(&self, s: &str) -> bool {
         self.params.iter().any(|t| t.contains(s)) || self.ret.contains(s)
     }
 
@@ -521,7 +559,7 @@ impl fmt::Display for FunTySig {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct PathSeg {
     ident: String,
     args: Vec<Type>,
@@ -591,7 +629,7 @@ impl fmt::Display for PathSeg {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Type {
     Slice(Box<Type>),
     Array(Box<Type>, String),
@@ -864,7 +902,7 @@ fn fmt_list<T: fmt::Display, I: Iterator<Item = T>>(
     write!(f, "{}", end)
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParsedItem {
     pub name: String,
     pub code: String,
@@ -881,7 +919,8 @@ impl ParsedItem {
                     .map(|s| s.as_str())
                     .collect::<Vec<_>>()
                     .join(", ");
-                return format!("#[derive({})]\n{}", s, self.code);
+                let (doc, code) = split_doc(&self.code);
+                return format!("{}#[derive({})]\n{}", doc, s, code);
             }
         }
         self.code.clone()
@@ -900,6 +939,7 @@ impl ParsedItem {
                 )
             }
             ItemSort::Function(f) => format!("{};", f.signature),
+            ItemSort::Macro => self.code.clone(),
             ItemSort::Use => panic!(),
         }
     }
@@ -922,6 +962,7 @@ impl ParsedItem {
                 )
             }
             ItemSort::Function(f) => format!("{} {{ todo!() }}", f.signature),
+            ItemSort::Macro => self.code.clone(),
             ItemSort::Use => panic!(),
         }
     }
@@ -935,21 +976,23 @@ impl ParsedItem {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ItemSort {
     Type(TypeInfo),
     Variable(VariableInfo),
     Function(FunctionInfo),
+    /// A `macro_rules!` definition.
+    Macro,
     Use,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TypeInfo {
     pub sort: TypeSort,
     pub derives: BTreeSet<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VariableInfo {
     pub is_const: bool,
     pub is_mutable: bool,
@@ -957,12 +1000,22 @@ pub struct VariableInfo {
     pub ty_str: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionInfo {
     pub signature: String,
     pub signature_ty: FunTySig,
 }
 
+/// Splits the code of an item into its leading doc comment and the rest.
+pub fn split_doc(code: &str) -> (&str, &str) {
+    let doc = code
+        .lines()
+        .take_while(|line| line.starts_with("///"))
+        .map(|line| line.len() + 1)
+        .sum::<usize>();
+    code.split_at(doc.min(code.len()))
+}
+
 pub fn parse(code: &str) -> Option<Vec<ParsedItem>> {
     let config = make_config(code);
     run_compiler(config, |compiler| {
@@ -1046,6 +1099,7 @@ pub fn parse(code: &str) -> Option<Vec<ParsedItem>> {
                             }
                             ItemSort::Use
                         }
+                        ItemKind::Macro(_, _) => ItemSort::Macro,
                         _ => continue,
                     };
                     items.push(ParsedItem {
@@ -1140,6 +1194,103 @@ pub fn resolve_free_consts(code: &str, quiet: bool) -> Option<String> {
     Some(rustfix::apply_suggestions(code, &suggestions).unwrap())
 }
 
+/// Redirects calls of undefined functions to the unique most similar function in `prefix`.
+pub fn resolve_free_functions(
+    code: &str,
+    prefix: &str,
+    callees: &[&str],
+    quiet: bool,
+) -> Option<String> {
+    let full_code = format!("{}{}", prefix, code);
+    let config = make_config(&full_code);
+    let suggestions: Vec<_> = run_compiler(config, |compiler| {
+        compiler.enter(|queries| {
+            queries.global_ctxt().ok()?.enter(|tcx| {
+                let mut visitor = FreeFunctionVisitor::new(tcx);
+                tcx.hir().visit_all_item_likes_in_crate(&mut visitor);
+                let arities = function_arities(tcx);
+                let source_map = tcx.sess.source_map();
+                let suggestions = visitor
+                    .undefined_functions
+                    .into_iter()
+                    .filter_map(|(span, args)| {
+                        let s = source_map.span_to_snippet(span).unwrap();
+                        let callee = similar_callee(&s, args, callees, &arities)?;
+                        if !quiet {
+                            println!("free function: {} -> {}", s, callee);
+                        }
+                        tracing::info!("free function: {} -> {}", s, callee);
+                        let snippet = span_to_snippet(span, source_map);
+                        Some(make_suggestion(snippet, callee))
+                    })
+                    .collect();
+                Some(suggestions)
+            })
+        })
+    })??;
+    let full_code = rustfix::apply_suggestions(&full_code, &suggestions).expect(&full_code);
+    Some(full_code.strip_prefix(prefix).unwrap().to_string())
+}
+
+/// The paths of the undefined functions called in `code`.
+pub fn free_functions(code: &str) -> Option<Vec<String>> {
+    let config = make_config(code);
+    run_compiler(config, |compiler| {
+        compiler.enter(|queries| {
+            queries.global_ctxt().ok()?.enter(|tcx| {
+                let mut visitor = FreeFunctionVisitor::new(tcx);
+                tcx.hir().visit_all_item_likes_in_crate(&mut visitor);
+                let source_map = tcx.sess.source_map();
+                let mut names: Vec<_> = visitor
+                    .undefined_functions
+                    .into_iter()
+                    .map(|(span, _)| source_map.span_to_snippet(span).unwrap())
+                    .collect();
+                names.sort();
+                names.dedup();
+                Some(names)
+            })
+        })
+    })?
+}
+
+fn function_arities(tcx: TyCtxt<'_>) -> BTreeMap<String, usize> {
+    let hir = tcx.hir();
+    hir.items()
+        .filter_map(|id| {
+            let item = hir.item(id);
+            let ItemKind::Fn(sig, _, _) = &item.kind else { return None };
+            Some((item.ident.name.to_ident_string(), sig.decl.inputs.len()))
+        })
+        .collect()
+}
+
+fn similar_callee<'a>(
+    path: &str,
+    args: usize,
+    callees: &[&'a str],
+    arities: &BTreeMap<String, usize>,
+) -> Option<&'a str> {
+    let normalize = |s: &str| s.replace('_', "").to_lowercase();
+    let name = normalize(path.rsplit("::").next().unwrap());
+    let limit = name.len().max(3) / 3;
+    let mut candidates: Vec<_> = callees
+        .iter()
+        .filter(|callee| arities.get(**callee) == Some(&args))
+        .filter_map(|callee| {
+            let dist = edit_distance_with_substrings(&name, &normalize(callee), limit)?;
+            Some((dist, *callee))
+        })
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    match candidates[..] {
+        [(_, callee)] => Some(callee),
+        [(d1, callee), (d2, _), ..] if d1 < d2 => Some(callee),
+        _ => None,
+    }
+}
+
 pub fn resolve_free_types(code: &str, prefix: &str, quiet: bool) -> Option<String> {
     let full_code = format!("{}{}", prefix, code);
     let config = make_config(&full_code);
@@ -1149,43 +1300,50 @@ pub fn resolve_free_types(code: &str, prefix: &str, quiet: bool) -> Option<Strin
                 let mut visitor = FreeTypeVisitor::new(tcx);
                 tcx.hir().visit_all_item_likes_in_crate(&mut visitor);
                 let source_map = compiler.session().source_map();
-                let types = visitor.undefined_types.into_iter().map(|(span, args)| {
-                    let s = source_map.span_to_snippet(span).unwrap();
-                    let replacement = if let Some(t) = LIBC_TYPE_ALIASES.get(s.as_str()) {
-                        t.to_string()
-                    } else if let Some(t) = STD_TYPES.get(s.as_str()) {
-                        t.to_string()
-                    } else if LIBC_TYPES.contains(s.as_str()) {
-                        format!("libc::{}", s)
-                    } else {
-                        match s.as_str() {
-                            "int" => "i32",
-                            "std::os::unix::raw::c_void" | "void" | "Void" => "libc::c_void",
-                            "std::os::unix::prelude::Pid" | "Pid" => "libc::pid_t",
-                            "TimeVal" | "time::TimeVal" => "libc::timeval",
-                            "__sighandler_t" | "libc::__sighandler_t" => "libc::sighandler_t",
-                            "SockaddrStorage" => "libc::sockaddr_storage",
-                            "AddrInfo" => "libc::addrinfo",
-                            "Passwd" | "libc::Passwd" => "libc::passwd",
-                            "std::os::raw::ptrdiff_t" => "libc::ptrdiff_t",
-                            _ => {
-                                if !quiet {
-                                    println!("free type: {}", s);
-                                }
-                                match args {
-                                    0 => "usize",
-                                    1 => "Box",
-                                    2 => "std::collections::HashMap",
-                                    _ => panic!("{} {}", s, args),
+                let types = visitor
+                    .undefined_types
+                    .into_iter()
+                    .filter_map(|(span, args)| {
+                        let s = source_map.span_to_snippet(span).unwrap();
+                        // Rust has no 128-bit float, so the type stays undefined
+                        if matches!(s.as_str(), "_Float128" | "__float128") {
+                            return None;
+                        }
+                        let replacement = if let Some(t) = LIBC_TYPE_ALIASES.get(s.as_str()) {
+                            t.to_string()
+                        } else if let Some(t) = STD_TYPES.get(s.as_str()) {
+                            t.to_string()
+                        } else if LIBC_TYPES.contains(s.as_str()) {
+                            format!("libc::{}", s)
+                        } else {
+                            match s.as_str() {
+                                "int" => "i32",
+                                "std::os::unix::raw::c_void" | "void" | "Void" => "libc::c_void",
+                                "std::os::unix::prelude::Pid" | "Pid" => "libc::pid_t",
+                                "TimeVal" | "time::TimeVal" => "libc::timeval",
+                                "__sighandler_t" | "libc::__sighandler_t" => "libc::sighandler_t",
+                                "SockaddrStorage" => "libc::sockaddr_storage",
+                                "AddrInfo" => "libc::addrinfo",
+                                "Passwd" | "libc::Passwd" => "libc::passwd",
+                                "std::os::raw::ptrdiff_t" => "libc::ptrdiff_t",
+                                _ => {
+                                    if !quiet {
+                                        println!("free type: {}", s);
+                                    }
+                                    match args {
+                                        0 => "usize",
+                                        1 => "Box",
+                                        2 => "std::collections::HashMap",
+                                        _ => panic!("{} {}", s, args),
+                                    }
                                 }
                             }
-                        }
-                        .to_string()
-                    };
-                    tracing::info!("free type: {} -> {}", s, replacement);
-                    let snippet = span_to_snippet(span, source_map);
-                    make_suggestion(snippet, &replacement)
-                });
+                            .to_string()
+                        };
+                        tracing::info!("free type: {} -> {}", s, replacement);
+                        let snippet = span_to_snippet(span, source_map);
+                        Some(make_suggestion(snippet, &replacement))
+                    });
                 let traits = visitor.undefined_traits.into_iter().map(|(span, _)| {
                     let s = source_map.span_to_snippet(span).unwrap();
                     let replacement = if let Some(t) = STD_TRAITS.get(s.as_str()) {
@@ -1253,6 +1411,36 @@ pub fn resolve_imports(code: &str, prefix: &str) -> Option<String> {
     Some(full_code.strip_prefix(prefix).unwrap().to_string())
 }
 
+/// Puts `helpers` at the start of the body of the function in `code`.
+pub fn nest_functions(code: &str, helpers: &[String]) -> Option<String> {
+    if helpers.is_empty() {
+        return Some(code.to_string());
+    }
+    let config = make_config(code);
+    let suggestion = run_compiler(config, |compiler| {
+        compiler.enter(|queries| {
+            queries.global_ctxt().ok()?.enter(|tcx| {
+                let hir = tcx.hir();
+                let source_map = compiler.session().source_map();
+                let body_id = hir.items().find_map(|id| match hir.item(id).kind {
+                    ItemKind::Fn(_, _, body_id) => Some(body_id),
+                    _ => None,
+                })?;
+                let span = hir.body(body_id).value.span;
+                let span = span.with_hi(span.lo() + BytePos(1));
+                let helpers: String = helpers
+                    .iter()
+                    .flat_map(|helper| helper.lines())
+                    .map(|line| format!("\n    {}", line).trim_end().to_string())
+                    .collect();
+                let snippet = span_to_snippet(span, source_map);
+                Some(make_suggestion(snippet, &format!("{{{}", helpers)))
+            })
+        })
+    })??;
+    Some(rustfix::apply_suggestions(code, &[suggestion]).expect(code))
+}
+
 pub fn rename_params(code: &str) -> Option<String> {
     let config = make_config(code);
     let suggestions: Vec<_> = run_compiler(config, |compiler| {
@@ -1350,6 +1538,51 @@ pub fn add_trait_uses<'i, I: IntoIterator<Item = &'i String>>(
     Some(rustfix::apply_suggestions(code, &suggestions).unwrap())
 }
 
+pub fn make_public(code: &str) -> Option<String> {
+    let config = make_config(code);
+    let suggestions: Vec<_> = run_compiler(config, |compiler| {
+        compiler.enter(|queries| {
+            queries.global_ctxt().ok()?.enter(|tcx| {
+                let hir = tcx.hir();
+                let source_map = compiler.session().source_map();
+                let mut spans = vec![];
+                for id in hir.items() {
+                    let item = hir.item(id);
+                    if tcx.local_parent(item.owner_id.def_id) != CRATE_DEF_ID
+                        || matches!(
+                            item.kind,
+                            ItemKind::Use(_, _)
+                                | ItemKind::ExternCrate(_)
+                                | ItemKind::Impl(_)
+                                | ItemKind::Macro(_, _)
+                        )
+                    {
+                        continue;
+                    }
+                    spans.push(item.vis_span);
+                    if let ItemKind::Struct(data, _) | ItemKind::Union(data, _) = &item.kind {
+                        spans.extend(data.fields().iter().map(|field| field.vis_span));
+                    }
+                }
+                // rustfix cannot insert at the very beginning of the code, so each suggestion
+                // also replaces the first character after the missing visibility.
+                let suggestions = spans
+                    .into_iter()
+                    .filter(|span| span.is_empty() && internal_span(*span, source_map))
+                    .map(|span| {
+                        let snippet =
+                            span_to_snippet(span.with_hi(span.hi() + BytePos(1)), source_map);
+                        let replacement = format!("pub {}", snippet.text.1);
+                        make_suggestion(snippet, &replacement)
+                    })
+                    .collect();
+                Some(suggestions)
+            })
+        })
+    })??;
+    Some(rustfix::apply_suggestions(code, &suggestions).unwrap())
+}
+
 pub fn resolve_sync(code: &str, prefix: &str) -> Option<String> {
     let inner = EmitterInner::default();
     let inner = Arc::new(Mutex::new(inner));
@@ -1484,13 +1717,94 @@ pub fn get_types(code: &str) -> Option<Vec<String>> {
     })?
 }
 
+/// The unsafe code in a function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnsafeCount {
+    // the body of an `unsafe fn` counts as one
+    pub blocks: usize,
+    pub raw_derefs: usize,
+    pub static_muts: usize,
+}
+
+impl UnsafeCount {
+    pub fn total(&self) -> usize {
+        self.blocks + self.raw_derefs + self.static_muts
+    }
+}
+
+/// Counts the unsafe code of each function; raw derefs are found only if `code` type-checks.
+pub fn count_unsafe(code: &str) -> Option<BTreeMap<String, UnsafeCount>> {
+    let config = make_config(code);
+    run_compiler(config, |compiler| {
+        compiler.enter(|queries| {
+            queries.global_ctxt().ok()?.enter(|tcx| {
+                let hir = tcx.hir();
+                let mut counts = BTreeMap::new();
+                for id in hir.items() {
+                    let item = hir.item(id);
+                    let ItemKind::Fn(sig, _, body_id) = &item.kind else { continue };
+                    let mut visitor = UnsafeVisitor::new(tcx, tcx.typeck_body(*body_id));
+                    if sig.header.unsafety == Unsafety::Unsafe {
+                        visitor.count.blocks += 1;
+                    }
+                    visitor.visit_body(hir.body(*body_id));
+                    counts.insert(item.ident.name.to_ident_string(), visitor.count);
+                }
+                Some(counts)
+            })
+        })
+    })?
+}
+
+/// The checking stages in the order rustc runs them.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    #[default]
+    Resolve,
+    ItemTypes,
+    ImplWf,
+    Coherence,
+    TypeWf,
+    TypeCheck,
+    Bodies,
+    BorrowCheck,
+    Unsafety,
+    Lint,
+    Done,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Resolve => "resolve",
+            Self::ItemTypes => "item_types",
+            Self::ImplWf => "impl_wf",
+            Self::Coherence => "coherence",
+            Self::TypeWf => "type_wf",
+            Self::TypeCheck => "type_check",
+            Self::Bodies => "bodies",
+            Self::BorrowCheck => "borrow_check",
+            Self::Unsafety => "unsafety",
+            Self::Lint => "lint",
+            Self::Done => "done",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TypeCheckingResult {
-    pub stage: usize,
+    /// The stage that failed, or `Stage::Done`.
+    pub stage: Stage,
     pub errors: Vec<TypeError>,
+    /// Warnings of rustc's built-in lints.
+    pub lints: Vec<TypeError>,
 }
 
-pub const MAX_STAGE: usize = 6;
+pub const MAX_STAGE: Stage = Stage::Done;
 
 impl TypeCheckingResult {
     pub fn passed(&self) -> bool {
@@ -1500,6 +1814,7 @@ impl TypeCheckingResult {
 
 #[derive(Debug, Clone)]
 pub struct TypeError {
+    pub stage: Stage,
     pub message: String,
     pub line: Option<usize>,
     pub fix: Option<PossibleFix>,
@@ -1562,7 +1877,7 @@ pub fn type_check(code: &str) -> Option<TypeCheckingResult> {
     let mut config = make_config(code);
     config.parse_sess_created = Some(Box::new(|ps: &mut ParseSess| {
         ps.span_diagnostic = Handler::with_emitter(
-            false,
+            true,
             None,
             Box::new(CollectingEmitter::new(cloned_inner, ps.clone_source_map())),
         );
@@ -1590,57 +1905,72 @@ pub fn type_check(code: &str) -> Option<TypeCheckingResult> {
                 tcx.ensure().limits(());
                 tcx.ensure().stability_index(());
 
-                if tcx
-                    .sess
-                    .track_errors(|| {
-                        tcx.hir()
-                            .for_each_module(|module| tcx.ensure().collect_mod_item_types(module))
-                    })
-                    .is_err()
-                {
-                    return Some(0);
+                let fails = |stage: Stage, f: &mut dyn FnMut()| {
+                    inner.lock().unwrap().stage = stage;
+                    tcx.sess.track_errors(f).is_err()
+                };
+                if fails(Stage::ItemTypes, &mut || {
+                    tcx.hir()
+                        .for_each_module(|module| tcx.ensure().collect_mod_item_types(module))
+                }) {
+                    return Some(Stage::ItemTypes);
                 }
-                if tcx
-                    .sess
-                    .track_errors(|| {
-                        tcx.hir()
-                            .for_each_module(|module| tcx.ensure().check_mod_impl_wf(module))
-                    })
-                    .is_err()
-                {
-                    return Some(1);
+                if fails(Stage::ImplWf, &mut || {
+                    tcx.hir()
+                        .for_each_module(|module| tcx.ensure().check_mod_impl_wf(module))
+                }) {
+                    return Some(Stage::ImplWf);
                 }
                 #[allow(clippy::blocks_in_if_conditions)]
-                if tcx
-                    .sess
-                    .track_errors(|| {
-                        for &trait_def_id in tcx.all_local_trait_impls(()).keys() {
-                            tcx.ensure().coherent_trait(trait_def_id);
-                        }
-                        tcx.ensure().crate_inherent_impls(());
-                        tcx.ensure().crate_inherent_impls_overlap_check(());
-                    })
-                    .is_err()
-                {
-                    return Some(2);
+                if fails(Stage::Coherence, &mut || {
+                    for &trait_def_id in tcx.all_local_trait_impls(()).keys() {
+                        tcx.ensure().coherent_trait(trait_def_id);
+                    }
+                    tcx.ensure().crate_inherent_impls(());
+                    tcx.ensure().crate_inherent_impls_overlap_check(());
+                }) {
+                    return Some(Stage::Coherence);
                 }
-                if tcx
-                    .sess
-                    .track_errors(|| {
-                        tcx.hir()
-                            .par_for_each_module(|module| tcx.ensure().check_mod_type_wf(module))
-                    })
-                    .is_err()
-                {
-                    return Some(3);
+                if fails(Stage::TypeWf, &mut || {
+                    tcx.hir()
+                        .par_for_each_module(|module| tcx.ensure().check_mod_type_wf(module))
+                }) {
+                    return Some(Stage::TypeWf);
                 }
+                inner.lock().unwrap().stage = Stage::TypeCheck;
                 if rustc_hir_analysis::check_crate(tcx).is_err() {
-                    return Some(4);
+                    return Some(Stage::TypeCheck);
                 }
+                if fails(Stage::Bodies, &mut || {
+                    tcx.hir().par_body_owners(|def_id| {
+                        tcx.ensure().check_match(def_id.to_def_id());
+                        tcx.ensure().check_liveness(def_id.to_def_id());
+                    })
+                }) {
+                    return Some(Stage::Bodies);
+                }
+                if fails(Stage::BorrowCheck, &mut || {
+                    tcx.hir()
+                        .par_body_owners(|def_id| tcx.ensure().mir_borrowck(def_id))
+                }) {
+                    return Some(Stage::BorrowCheck);
+                }
+                if fails(Stage::Unsafety, &mut || {
+                    for def_id in tcx.hir().body_owners() {
+                        tcx.ensure().thir_check_unsafety(def_id);
+                        if !tcx.sess.opts.unstable_opts.thir_unsafeck {
+                            rustc_mir_transform::check_unsafety::check_unsafety(tcx, def_id);
+                        }
+                    }
+                }) {
+                    return Some(Stage::Unsafety);
+                }
+                // the rest of the analysis, including the lint pass
+                inner.lock().unwrap().stage = Stage::Lint;
                 if tcx.analysis(()).is_err() {
-                    return Some(5);
+                    return Some(Stage::Lint);
                 }
-                Some(6)
+                Some(Stage::Done)
             })
         })?;
         let mut errors = vec![];
@@ -1732,10 +2062,39 @@ pub fn type_check(code: &str) -> Option<TypeCheckingResult> {
                 };
                 Some(fix)
             });
-            let error = TypeError { message, line, fix };
+            let error = TypeError {
+                stage: diag.stage,
+                message,
+                line,
+                fix,
+            };
             errors.push(error);
         }
-        Some(TypeCheckingResult { stage, errors })
+        let lints = inner
+            .lock()
+            .unwrap()
+            .lints
+            .iter()
+            .map(|diag| {
+                let message = format!("{}", WithSourceMap::new(source_map, diag));
+                TypeError {
+                    stage: diag.stage,
+                    message: message
+                        .lines()
+                        .filter(|s| !s.trim().is_empty())
+                        .intersperse("\n")
+                        .collect(),
+                    line: diag.span.primary_line(source_map),
+                    fix: None,
+                }
+            })
+            .collect();
+        let stage = errors.iter().map(|e| e.stage).fold(stage, Stage::min);
+        Some(TypeCheckingResult {
+            stage,
+            errors,
+            lints,
+        })
     })?
 }
 
@@ -1834,6 +2193,39 @@ impl<'tcx> Visitor<'tcx> for FreeConstantVisitor<'tcx> {
     }
 }
 
+struct FreeFunctionVisitor<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    undefined_functions: Vec<(Span, usize)>,
+}
+
+impl<'tcx> FreeFunctionVisitor<'tcx> {
+    fn new(tcx: TyCtxt<'tcx>) -> Self {
+        Self {
+            tcx,
+            undefined_functions: vec![],
+        }
+    }
+}
+
+impl<'tcx> Visitor<'tcx> for FreeFunctionVisitor<'tcx> {
+    type NestedFilter = nested_filter::OnlyBodies;
+
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.tcx.hir()
+    }
+
+    fn visit_expr(&mut self, e: &'tcx Expr<'tcx>) {
+        if let ExprKind::Call(callee, args) = &e.kind {
+            if let ExprKind::Path(QPath::Resolved(_, p)) = &callee.kind {
+                if p.res == Res::Err && !callee.span.from_expansion() {
+                    self.undefined_functions.push((callee.span, args.len()));
+                }
+            }
+        }
+        intravisit::walk_expr(self, e);
+    }
+}
+
 struct RecursiveVarVisitor<'tcx> {
     tcx: TyCtxt<'tcx>,
     items: Vec<Option<LocalDefId>>,
@@ -2051,6 +2443,59 @@ impl<'tcx> Visitor<'tcx> for TypeVisitor<'tcx> {
     }
 }
 
+struct UnsafeVisitor<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    typeck: &'tcx TypeckResults<'tcx>,
+    count: UnsafeCount,
+}
+
+impl<'tcx> UnsafeVisitor<'tcx> {
+    fn new(tcx: TyCtxt<'tcx>, typeck: &'tcx TypeckResults<'tcx>) -> Self {
+        Self {
+            tcx,
+            typeck,
+            count: UnsafeCount::default(),
+        }
+    }
+}
+
+impl<'tcx> Visitor<'tcx> for UnsafeVisitor<'tcx> {
+    type NestedFilter = nested_filter::OnlyBodies;
+
+    fn nested_visit_map(&mut self) -> Self::Map {
+        self.tcx.hir()
+    }
+
+    fn visit_block(&mut self, b: &'tcx Block<'tcx>) {
+        // unsafe code that macros generate is not the function's own
+        if b.rules == BlockCheckMode::UnsafeBlock(UnsafeSource::UserProvided)
+            && !b.span.from_expansion()
+        {
+            self.count.blocks += 1;
+        }
+        intravisit::walk_block(self, b);
+    }
+
+    fn visit_expr(&mut self, e: &'tcx Expr<'tcx>) {
+        if !e.span.from_expansion() {
+            match &e.kind {
+                ExprKind::Unary(UnOp::Deref, inner)
+                    if self.typeck.expr_ty_adjusted(inner).is_unsafe_ptr() =>
+                {
+                    self.count.raw_derefs += 1;
+                }
+                ExprKind::Path(QPath::Resolved(_, path))
+                    if matches!(path.res, Res::Def(DefKind::Static(Mutability::Mut), _)) =>
+                {
+                    self.count.static_muts += 1;
+                }
+                _ => (),
+            }
+        }
+        intravisit::walk_expr(self, e);
+    }
+}
+
 pub fn overlap(s1: &Suggestion, s2: &Suggestion) -> bool {
     let r1 = &s1.snippets[0].range;
     let r2 = &s2.snippets[0].range;
@@ -2271,6 +2716,14 @@ mod tests {
             resolve_free_types("fn foo() -> Path {}", "use std::path::Path;", true).unwrap(),
             "fn foo() -> Path {}"
         );
+        assert_eq!(
+            resolve_free_types("fn foo(x: __uint128_t) -> __int128_t {}", "", true).unwrap(),
+            "fn foo(x: u128) -> i128 {}"
+        );
+        assert_eq!(
+            resolve_free_types("fn foo(x: _Float128) -> usize {}", "", true).unwrap(),
+            "fn foo(x: _Float128) -> usize {}"
+        );
         assert_eq!(
             resolve_free_types("fn foo() -> Rc<usize> {}", "", true).unwrap(),
             "fn foo() -> std::rc::Rc<usize> {}"
@@ -2297,6 +2750,125 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_free_functions() {
+        let prefix = "fn main() {}
+fn list_insert(l: i32, x: i32) -> i32 { todo!() }
+fn list_len(l: i32) -> i32 { todo!() }
+";
+        let code =
+            "fn f() -> i32 { listInsert(1, 2) + ListLength(1) + lst_len(1, 2) + missing(3) }";
+        let callees = ["list_insert", "list_len"];
+        let resolved = resolve_free_functions(code, prefix, &callees, true).unwrap();
+        assert_eq!(
+            resolved,
+            "fn f() -> i32 { list_insert(1, 2) + list_len(1) + lst_len(1, 2) + missing(3) }"
+        );
+        assert_eq!(
+            free_functions(&format!("{}{}", prefix, resolved)).unwrap(),
+            vec!["lst_len", "missing"]
+        );
+    }
+
+    #[test]
+    fn test_nest_functions() {
+        let code = "fn f(x: i32) -> i32 {\n    if x < 0 {\n        return fail();\n    }\n    x\n}";
+        let helpers = ["fn fail() -> i32 {\n    -1\n}".to_string()];
+        let nested = nest_functions(code, &helpers).unwrap();
+        assert_eq!(
+            nested,
+            "fn f(x: i32) -> i32 {\n    fn fail() -> i32 {\n        -1\n    }\n    if x < 0 {\n        return fail();\n    }\n    x\n}"
+        );
+        let items = parse(&nested).unwrap();
+        assert_eq!(items.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_macro() {
+        let items =
+            parse("macro_rules! sq { ($x:expr) => { $x * $x }; }\nconst N: i32 = sq!(2);").unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[0].sort, ItemSort::Macro));
+        assert_eq!(items[0].name, "sq");
+        assert_eq!(
+            items[0].get_simple_code(),
+            "macro_rules! sq { ($x:expr) => { $x * $x }; }"
+        );
+    }
+
+    #[test]
+    fn test_stages() {
+        let stage = |code: &str| {
+            let res = type_check(&format!("fn main() {{}}\n{}", code)).unwrap();
+            assert!(res.errors.iter().all(|e| e.stage == res.stage));
+            res
+        };
+        assert_eq!(stage("fn f() -> i32 { g() }").stage, Stage::Resolve);
+        assert_eq!(stage("fn f() -> i32 { \"\" }").stage, Stage::TypeCheck);
+        assert_eq!(
+            stage("fn f(x: Option<i32>) -> i32 { match x { Some(y) => y } }").stage,
+            Stage::Bodies
+        );
+        assert_eq!(
+            stage("fn f() -> String { let s = String::new(); let t = s; s }").stage,
+            Stage::BorrowCheck
+        );
+        assert_eq!(
+            stage("fn f(p: *const i32) -> i32 { *p }").stage,
+            Stage::Unsafety
+        );
+        let res = stage("pub fn f() { let x = 1; }");
+        assert_eq!(res.stage, Stage::Done);
+        assert!(res.lints[0]
+            .message
+            .starts_with("warning[unused_variables]"));
+        assert_eq!(res.lints[0].line, Some(2));
+        let res = type_check("fn main() { let x: i32 = \"\"; g(x) }").unwrap();
+        let stages: BTreeSet<_> = res.errors.iter().map(|e| e.stage).collect();
+        assert_eq!(stages, BTreeSet::from([Stage::Resolve, Stage::TypeCheck]));
+        let res = stage("pub fn f() { unsafe {} }");
+        assert_eq!(res.lints.len(), 1);
+        assert!(res.lints[0].message.starts_with("warning[unused_unsafe]"));
+    }
+
+    #[test]
+    fn test_count_unsafe() {
+        let code = "static mut N: i32 = 0;
+        fn f(p: *mut i32) -> i32 {
+            unsafe {
+                *p += N;
+                N = *p;
+            }
+            println!(\"{}\", unsafe { N });
+            0
+        }
+        unsafe fn g(p: *const i32) -> i32 { *p }
+        fn h(x: &i32) -> i32 { *x }";
+        let counts = count_unsafe(code).unwrap();
+        let count = |blocks, raw_derefs, static_muts| UnsafeCount {
+            blocks,
+            raw_derefs,
+            static_muts,
+        };
+        assert_eq!(counts["f"], count(2, 2, 3));
+        assert_eq!(counts["g"], count(1, 1, 0));
+        assert_eq!(counts["h"], UnsafeCount::default());
+        assert_eq!(counts["f"].total(), 7);
+    }
+
+    #[test]
+    fn test_make_public() {
+        assert_eq!(
+            make_public("static mut X: i32 = 0;").unwrap(),
+            "pub static mut X: i32 = 0;"
+        );
+        let code = "#[derive(Clone)]\nstruct A { x: i32, pub y: i32 }\nfn f(a: A) -> i32 { use std::cmp::max; max(a.x, a.y) }\npub const C: i32 = 0;";
+        assert_eq!(
+            make_public(code).unwrap(),
+            "#[derive(Clone)]\npub struct A { pub x: i32, pub y: i32 }\npub fn f(a: A) -> i32 { use std::cmp::max; max(a.x, a.y) }\npub const C: i32 = 0;"
+        );
+    }
+
     #[test]
     fn test_import() {
         assert_eq!(
@@ -2381,6 +2953,17 @@ fn raw_to_map(arr: &'static [&'static str]) -> BTreeMap<&'static str, &'static s
     map
 }
 
+pub fn is_prelude_name(name: &str) -> bool {
+    PRELUDES
+        .iter()
+        .any(|path| path.rsplit("::").next() == Some(name))
+}
+
+/// Whether the name is in the prelude or names a type or trait of `std`.
+pub fn is_std_name(name: &str) -> bool {
+    is_prelude_name(name) || STD_TYPES.contains_key(name) || STD_TRAITS.contains_key(name)
+}
+
 fn is_primitive(ty: &str) -> bool {
     C_PRIMITIVE_SUFFIXES.iter().any(|p| ty.ends_with(p))
 }
@@ -2389,26 +2972,30 @@ pub fn is_c_type(ty: &str) -> bool {
     ty == "primitive::ptr" || C_TYPE_PREFIXES.iter().any(|p| ty.starts_with(p))
 }
 
-static INT_TYPES_RAW: [&str; 46] = [
+static INT_TYPES_RAW: [&str; 50] = [
     "char",
     "u8",
     "u16",
     "u32",
     "u64",
+    "u128",
     "usize",
     "i8",
     "i16",
     "i32",
     "i64",
+    "i128",
     "isize",
     "__s16",
     "__s32",
+    "__int128_t",
     "__s64",
     "__syscall_ulong_t",
     "__u8",
     "__u16",
     "__u32",
     "__u64",
+    "__uint128_t",
     "c_char",
     "c_int",
     "c_long",
@@ -2758,15 +3345,17 @@ static LIBC_TYPES_RAW: [&str; 278] = [
     "wchar_t",
 ];
 
-static LIBC_TYPE_ALIASES_RAW: [(&str, &str); 37] = [
+static LIBC_TYPE_ALIASES_RAW: [(&str, &str); 39] = [
     ("__s16", "i16"),
     ("__s32", "i32"),
+    ("__int128_t", "i128"),
     ("__s64", "i64"),
     ("__syscall_ulong_t", "u64"),
     ("__u8", "u8"),
     ("__u16", "u16"),
     ("__u32", "u32"),
     ("__u64", "u64"),
+    ("__uint128_t", "u128"),
     ("c_char", "u8"),
     ("c_double", "double"),
     ("c_float", "float"),
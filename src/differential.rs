@@ -0,0 +1,442 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use etrace::some_or;
+
+use crate::{
+    c_parser,
+    compiler::{self, FunTySig},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scalar {
+    Signed,
+    Unsigned,
+    Bool,
+    Float,
+}
+
+/// The scalar kind of a Rust type written as a path, e.g. `i32` or `libc::c_uint`.
+pub fn scalar_kind(ty: &str) -> Option<Scalar> {
+    let ty = ty.rsplit("::").next().unwrap();
+    match ty {
+        "i8" | "i16" | "i32" | "i64" | "i128" | "isize" | "c_char" | "c_schar" | "c_short"
+        | "c_int" | "c_long" | "c_longlong" | "ssize_t" => Some(Scalar::Signed),
+        "u8" | "u16" | "u32" | "u64" | "u128" | "usize" | "c_uchar" | "c_ushort" | "c_uint"
+        | "c_ulong" | "c_ulonglong" | "size_t" => Some(Scalar::Unsigned),
+        "bool" => Some(Scalar::Bool),
+        "f32" | "f64" | "c_float" | "c_double" => Some(Scalar::Float),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    Int(i64),
+    Float(f64),
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
+
+impl Arg {
+    fn from_json(v: &serde_json::Value, kind: Scalar) -> Option<Self> {
+        if kind == Scalar::Float {
+            Some(Self::Float(v.as_f64()?))
+        } else {
+            Some(Self::Int(
+                v.as_i64().or_else(|| v.as_bool().map(i64::from))?,
+            ))
+        }
+    }
+
+    fn c_literal(&self) -> String {
+        match self {
+            Self::Int(i) => format!("({}LL)", i),
+            Self::Float(x) => format!("({:?})", x),
+        }
+    }
+
+    fn rust_literal(&self, kind: Scalar) -> String {
+        match (self, kind) {
+            (Self::Int(i), Scalar::Bool) => format!("({}i64 != 0)", i),
+            (Self::Int(i), _) => format!("({}i64 as _)", i),
+            (Self::Float(x), _) => format!("({:?}f64 as _)", x),
+        }
+    }
+}
+
+/// A function whose C and Rust signatures consist of scalars only, so that both versions can be
+/// called with the same arguments and their results compared.
+#[derive(Debug)]
+pub struct Target {
+    pub name: String,
+    pub path: String,
+    rust_name: String,
+    params: Vec<Scalar>,
+    ret: Scalar,
+    is_unsafe: bool,
+    pub inputs: Vec<Vec<Arg>>,
+}
+
+const CASES: usize = 32;
+
+impl Target {
+    pub fn new(
+        name: &str,
+        path: &str,
+        rust_name: &str,
+        c_sig: &FunTySig,
+        rust_sig: &FunTySig,
+        rust_signature: &str,
+    ) -> Option<Self> {
+        if c_sig.params.len() != rust_sig.params.len() || rust_sig.generic {
+            return None;
+        }
+        let compatible = |c: &compiler::Type, r: &compiler::Type| {
+            let r = scalar_kind(&r.to_string())?;
+            match (c.to_string().as_str(), r) {
+                ("int", Scalar::Signed | Scalar::Unsigned | Scalar::Bool) => Some(r),
+                ("float", Scalar::Float) => Some(r),
+                _ => None,
+            }
+        };
+        let params = c_sig
+            .params
+            .iter()
+            .zip(&rust_sig.params)
+            .map(|(c, r)| compatible(c, r))
+            .collect::<Option<Vec<_>>>()?;
+        let ret = compatible(&c_sig.ret, &rust_sig.ret)?;
+        let mut target = Self {
+            name: name.to_string(),
+            path: path.to_string(),
+            rust_name: rust_name.to_string(),
+            params,
+            ret,
+            is_unsafe: rust_signature.contains("unsafe "),
+            inputs: vec![],
+        };
+        target.inputs = target.generate_inputs();
+        Some(target)
+    }
+
+    fn generate_inputs(&self) -> Vec<Vec<Arg>> {
+        let pool = |kind: Scalar| -> Vec<Arg> {
+            match kind {
+                Scalar::Signed => [0, 1, 2, 3, 7, 10, 42, 100, -1, -7]
+                    .into_iter()
+                    .map(Arg::Int)
+                    .collect(),
+                Scalar::Unsigned => [0, 1, 2, 3, 7, 10, 42, 100]
+                    .into_iter()
+                    .map(Arg::Int)
+                    .collect(),
+                Scalar::Bool => vec![Arg::Int(0), Arg::Int(1)],
+                Scalar::Float => [0.0, 1.5, -2.25, 3.0, 10.0, 0.1]
+                    .into_iter()
+                    .map(Arg::Float)
+                    .collect(),
+            }
+        };
+        let pools: Vec<_> = self.params.iter().map(|kind| pool(*kind)).collect();
+        // walks the cartesian product with a stride coprime to its size, so that the cases are
+        // distinct and each argument takes various values
+        let total: usize = pools.iter().map(|pool| pool.len()).product();
+        let gcd = |mut a: usize, mut b: usize| {
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            a
+        };
+        let stride = (7919..).find(|s| gcd(*s, total) == 1).unwrap();
+        (0..CASES.min(total))
+            .map(|i| {
+                let mut k = i * stride % total;
+                pools
+                    .iter()
+                    .map(|pool| {
+                        let arg = pool[k % pool.len()];
+                        k /= pool.len();
+                        arg
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Replaces the generated inputs with `cases`, given as JSON arrays of numbers.
+    pub fn set_inputs(&mut self, cases: &[serde_json::Value]) {
+        self.inputs = cases
+            .iter()
+            .filter_map(|case| {
+                let case = case.as_array()?;
+                if case.len() != self.params.len() {
+                    tracing::warn!("wrong number of arguments for {}: {:?}", self.name, case);
+                    return None;
+                }
+                case.iter()
+                    .zip(&self.params)
+                    .map(|(v, kind)| Arg::from_json(v, *kind))
+                    .collect()
+            })
+            .collect();
+    }
+
+    fn c_calls(&self) -> String {
+        self.inputs
+            .iter()
+            .map(|args| {
+                let args: Vec<_> = args.iter().map(|a| a.c_literal()).collect();
+                let call = format!("{}({})", c_parser::c_name(&self.name), args.join(", "));
+                if self.ret == Scalar::Float {
+                    format!("    simcrat_float({});\n", call)
+                } else {
+                    format!("    simcrat_int((long long) {});\n", call)
+                }
+            })
+            .collect()
+    }
+
+    fn rust_calls(&self) -> String {
+        self.inputs
+            .iter()
+            .map(|args| {
+                let args: Vec<_> = args
+                    .iter()
+                    .zip(&self.params)
+                    .map(|(a, kind)| a.rust_literal(*kind))
+                    .collect();
+                let call = format!("{}({})", self.rust_name, args.join(", "));
+                let call = if self.is_unsafe {
+                    format!("unsafe {{ {} }}", call)
+                } else {
+                    call
+                };
+                let value = if self.ret == Scalar::Float {
+                    format!("({} as f64).to_bits().to_string()", call)
+                } else {
+                    format!("({} as i64).to_string()", call)
+                };
+                format!("        simcrat_call(|| {});\n", value)
+            })
+            .collect()
+    }
+}
+
+/// Calls the functions of one translation unit, selected by the first command line argument,
+/// and prints one line per call without buffering so that the lines before a crash are kept.
+fn c_harness(code: &str, targets: &[(usize, &Target)]) -> String {
+    let cases: String = targets
+        .iter()
+        .map(|(i, t)| format!("  case {}:\n{}    break;\n", i, t.c_calls()))
+        .collect();
+    format!(
+        "#define main simcrat_c_main
+{}
+#undef main
+extern int dprintf(int, const char *, ...);
+static void simcrat_int(long long v) {{ dprintf(1, \"%lld\\n\", v); }}
+static void simcrat_float(double v) {{
+  union {{ double d; unsigned long long u; }} x;
+  x.d = v;
+  dprintf(1, \"%llu\\n\", x.u);
+}}
+int main(int argc, char **argv) {{
+  int n = 0;
+  for (char *p = argv[1]; *p; p++) n = n * 10 + *p - '0';
+  switch (n) {{
+{}  }}
+  return 0;
+}}
+",
+        code, cases
+    )
+}
+
+/// Like `c_harness`, but for the whole translated crate. A panicking call prints `panic`.
+fn rust_harness(code: &str, targets: &[Target]) -> String {
+    let cases: String = targets
+        .iter()
+        .enumerate()
+        .map(|(i, t)| format!("        {} => {{\n{}        }}\n", i, t.rust_calls()))
+        .collect();
+    format!(
+        "{}
+fn simcrat_call<F: FnOnce() -> String>(f: F) {{
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {{
+        Ok(s) => println!(\"{{}}\", s),
+        Err(_) => println!(\"panic\"),
+    }}
+}}
+#[allow(unused_unsafe)]
+fn main() {{
+    std::panic::set_hook(Box::new(|_| {{}}));
+    let n: usize = std::env::args().nth(1).unwrap().parse().unwrap();
+    match n {{
+{}        _ => {{}}
+    }}
+}}
+",
+        code, cases
+    )
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Same,
+    Differs {
+        args: String,
+        c: String,
+        rust: String,
+    },
+}
+
+fn compile(command: &mut Command) -> Result<(), String> {
+    let output = command.output().map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+}
+
+fn run_harness(exe: &Path, n: usize) -> Vec<String> {
+    let output = Command::new("timeout")
+        .arg("10")
+        .arg(exe)
+        .arg(n.to_string())
+        .output();
+    let output = some_or!(output.ok(), return vec![]);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Compiles `c_codes`, the preprocessed translation units, with gcc and `rust_code`, the
+/// translated crate without `main`, with rustc. Then runs both on the inputs of each target and
+/// compares the results. Calls that crash or time out in C are not compared.
+pub fn run(
+    c_codes: &BTreeMap<String, String>,
+    rust_code: &str,
+    targets: &[Target],
+) -> Result<BTreeMap<String, Outcome>, String> {
+    let dir = std::env::temp_dir().join(format!("simcrat-diff-{}", std::process::id()));
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let result = run_in(&dir, c_codes, rust_code, targets);
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn run_in(
+    dir: &Path,
+    c_codes: &BTreeMap<String, String>,
+    rust_code: &str,
+    targets: &[Target],
+) -> Result<BTreeMap<String, Outcome>, String> {
+    let rs_file = dir.join("harness.rs");
+    let rs_exe = dir.join("harness_rs");
+    fs::write(&rs_file, rust_harness(rust_code, targets)).map_err(|e| e.to_string())?;
+    compile(
+        Command::new("rustc")
+            .args(["--edition", "2021", "-A", "warnings", "-o"])
+            .arg(&rs_exe)
+            .arg(&rs_file)
+            .args(compiler::extern_args()),
+    )?;
+
+    let mut c_exes: BTreeMap<&str, PathBuf> = BTreeMap::new();
+    for (k, (path, code)) in c_codes.iter().enumerate() {
+        let in_tu: Vec<_> = targets
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| &t.path == path)
+            .collect();
+        if in_tu.is_empty() {
+            continue;
+        }
+        let c_file = dir.join(format!("harness_{}.c", k));
+        let c_exe = dir.join(format!("harness_c_{}", k));
+        fs::write(&c_file, c_harness(code, &in_tu)).map_err(|e| e.to_string())?;
+        let res = compile(
+            Command::new("gcc")
+                .args(["-w", "-o"])
+                .arg(&c_exe)
+                .arg(&c_file)
+                .args(["-lm", "-Wl,--unresolved-symbols=ignore-all"]),
+        );
+        match res {
+            Ok(()) => {
+                c_exes.insert(path, c_exe);
+            }
+            Err(e) => tracing::warn!("cannot compile the C harness of {}:\n{}", path, e),
+        }
+    }
+
+    let mut outcomes = BTreeMap::new();
+    for (i, target) in targets.iter().enumerate() {
+        let c_exe = some_or!(c_exes.get(target.path.as_str()), continue);
+        let c_lines = run_harness(c_exe, i);
+        let rust_lines = run_harness(&rs_exe, i);
+        let mut outcome = Outcome::Same;
+        for (j, c) in c_lines.iter().enumerate() {
+            let rust = rust_lines.get(j).map(|s| s.as_str()).unwrap_or("crash");
+            if c != rust {
+                let args: Vec<_> = target.inputs[j].iter().map(|a| a.to_string()).collect();
+                outcome = Outcome::Differs {
+                    args: args.join(", "),
+                    c: c.clone(),
+                    rust: rust.to_string(),
+                };
+                break;
+            }
+        }
+        outcomes.insert(target.name.clone(), outcome);
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Type;
+
+    fn sig(params: &[&str], ret: &str) -> FunTySig {
+        FunTySig {
+            params: params
+                .iter()
+                .map(|p| Type::from_name(p.to_string()))
+                .collect(),
+            ret: Type::from_name(ret.to_string()),
+            generic: false,
+        }
+    }
+
+    #[test]
+    fn test_target() {
+        let c = sig(&["int", "float"], "int");
+        let rust = sig(&["usize", "f64"], "bool");
+        let target =
+            Target::new("f", "a.c", "f", &c, &rust, "fn f(x: usize, y: f64) -> bool").unwrap();
+        assert_eq!(target.params, [Scalar::Unsigned, Scalar::Float]);
+        assert_eq!(target.inputs.len(), CASES);
+        assert!(target
+            .inputs
+            .iter()
+            .all(|args| matches!(args[0], Arg::Int(i) if i >= 0)));
+
+        let rust = sig(&["usize", "i32"], "bool");
+        assert!(Target::new("f", "a.c", "f", &c, &rust, "").is_none());
+    }
+}
@@ -39,12 +39,20 @@ extern crate rustc_hir_analysis;
 extern crate rustc_index;
 extern crate rustc_interface;
 extern crate rustc_middle;
+extern crate rustc_mir_transform;
 extern crate rustc_passes;
 extern crate rustc_session;
 extern crate rustc_span;
 
 pub mod c_parser;
 pub mod compiler;
+pub mod differential;
 pub mod graph;
 pub mod llm_client;
+pub mod names;
+pub mod overrides;
+pub mod project;
+pub mod provenance;
+#[cfg(test)]
+mod testing;
 pub mod translation;
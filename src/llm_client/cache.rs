@@ -1,6 +1,17 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
+use async_trait::async_trait;
 use etrace::some_or;
+use futures::TryStreamExt;
 use mongodb::{
     bson::doc,
     options::{ClientOptions, Credential, ServerAddress},
@@ -12,6 +23,38 @@ pub trait HasElapsed {
     fn elapsed(&self) -> f32;
 }
 
+/// A cache value of any client, used when moving entries between backends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RawVal(pub serde_json::Value);
+
+impl HasElapsed for RawVal {
+    fn elapsed(&self) -> f32 {
+        self.0["elapsed"].as_f64().unwrap_or(0.0) as f32
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KindStat {
+    pub entries: usize,
+    pub tokens: usize,
+}
+
+pub fn kind_stats(entries: &[(serde_json::Value, RawVal)]) -> BTreeMap<String, KindStat> {
+    let mut stats: BTreeMap<String, KindStat> = BTreeMap::new();
+    for (_, RawVal(val)) in entries {
+        let kind = val["kind"].as_str().unwrap_or("unknown").to_string();
+        let tokens = ["request_tokens", "response_tokens"]
+            .iter()
+            .filter_map(|field| val[field].as_u64())
+            .sum::<u64>();
+        let stat = stats.entry(kind).or_default();
+        stat.entries += 1;
+        stat.tokens += tokens as usize;
+    }
+    stats
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct CacheData<K, V>
 where
@@ -38,13 +81,200 @@ where
     }
 }
 
+/// Adds a model tag and a prompt version to a key written before keys were versioned. Returns
+/// `None` if the key is already tagged. The fields come out sorted, so the key structs of the
+/// clients keep theirs in alphabetical order: MongoDB compares `_id` documents field by field.
+pub fn tag_key(key: &serde_json::Value, model: &str, version: u32) -> Option<serde_json::Value> {
+    let mut key = key.as_object()?.clone();
+    if key.contains_key("model") {
+        return None;
+    }
+    key.insert("model".to_string(), model.into());
+    key.insert("version".to_string(), version.into());
+    let key: BTreeMap<_, _> = key.into_iter().collect();
+    Some(key.into_iter().collect())
+}
+
+/// Checks that `key` serializes like its untagged form after `tag_key`.
+#[cfg(test)]
+pub(super) fn check_tag_key<K: Serialize>(key: &K) {
+    let mut untagged = serde_json::to_value(key)
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .clone();
+    let model = untagged.remove("model").unwrap();
+    let version = untagged.remove("version").unwrap();
+    let version = version.as_u64().unwrap() as u32;
+    let tagged = tag_key(&untagged.into(), model.as_str().unwrap(), version).unwrap();
+    assert_eq!(tagged.to_string(), serde_json::to_string(key).unwrap());
+}
+
+#[async_trait]
+pub trait CacheBackend<K, V>: Sync + Send
+where
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned,
+    V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+{
+    async fn get(&self, key: &K) -> Option<V>;
+    async fn insert(&self, key: K, value: V);
+    async fn entries(&self) -> Vec<(K, V)>;
+}
+
+pub struct MongoBackend<K, V>
+where
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned,
+    V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+{
+    collection: Collection<CacheData<K, V>>,
+}
+
+impl<K, V> MongoBackend<K, V>
+where
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned,
+    V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+{
+    pub fn new(
+        name: &str,
+        host: Option<String>,
+        port: Option<String>,
+        password: Option<String>,
+    ) -> Self {
+        let host = host.unwrap_or("localhost".to_string());
+        let port = port.unwrap_or("27017".to_string()).parse().ok();
+        let addr = ServerAddress::Tcp { host, port };
+        let credential = password.map(|password| {
+            Credential::builder()
+                .username("admin".to_string())
+                .password(password)
+                .build()
+        });
+        let client_options = ClientOptions::builder()
+            .hosts(vec![addr])
+            .credential(credential)
+            .build();
+        let client = MongoClient::with_options(client_options).unwrap();
+        let db = client.database(name);
+        let collection = db.collection::<CacheData<K, V>>("cache");
+        Self { collection }
+    }
+}
+
+#[async_trait]
+impl<K, V> CacheBackend<K, V> for MongoBackend<K, V>
+where
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned,
+    V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+{
+    async fn get(&self, key: &K) -> Option<V> {
+        let data = self
+            .collection
+            .find_one(doc! { "_id": mongodb::bson::to_bson(key).unwrap() }, None)
+            .await
+            .unwrap()?;
+        Some(data.val)
+    }
+
+    async fn insert(&self, key: K, value: V) {
+        let data = CacheData {
+            _id: key,
+            val: value,
+        };
+        let _ = self.collection.insert_one(data, None).await;
+    }
+
+    async fn entries(&self) -> Vec<(K, V)> {
+        let cursor = self.collection.find(None, None).await.unwrap();
+        let data: Vec<_> = cursor.try_collect().await.unwrap();
+        data.into_iter().map(|data| (data._id, data.val)).collect()
+    }
+}
+
+/// An append-only JSONL file with one `{"_id": .., "val": ..}` object per line, loaded into
+/// memory on creation.
+pub struct FileBackend<V> {
+    entries: Mutex<HashMap<String, V>>,
+    file: Mutex<File>,
+}
+
+impl<V> FileBackend<V>
+where V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed
+{
+    pub fn new(path: &str) -> Self {
+        let mut entries = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line.unwrap();
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let data: CacheData<serde_json::Value, V> = match serde_json::from_str(&line) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        tracing::warn!("skipping malformed cache entry in {}: {}", path, e);
+                        continue;
+                    }
+                };
+                entries.entry(data._id.to_string()).or_insert(data.val);
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        Self {
+            entries: Mutex::new(entries),
+            file: Mutex::new(file),
+        }
+    }
+}
+
+#[async_trait]
+impl<K, V> CacheBackend<K, V> for FileBackend<V>
+where
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned + 'static,
+    V: Clone + Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+{
+    async fn get(&self, key: &K) -> Option<V> {
+        let key = serde_json::to_value(key).unwrap().to_string();
+        self.entries.lock().unwrap().get(&key).cloned()
+    }
+
+    async fn insert(&self, key: K, value: V) {
+        let k = serde_json::to_value(&key).unwrap().to_string();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&k) {
+            return;
+        }
+        let data = CacheData {
+            _id: key,
+            val: value,
+        };
+        let line = serde_json::to_string(&data).unwrap();
+        writeln!(self.file.lock().unwrap(), "{}", line).unwrap();
+        entries.insert(k, data.val);
+    }
+
+    async fn entries(&self) -> Vec<(K, V)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (serde_json::from_str(k).unwrap(), v.clone()))
+            .collect()
+    }
+}
+
 pub struct Cache<K, V>
 where
     K: Sync + Send + Unpin + Serialize + DeserializeOwned,
     V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
 {
-    collection: Option<Collection<CacheData<K, V>>>,
+    backend: Option<Box<dyn CacheBackend<K, V>>>,
     real_time: bool,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
 }
 
 pub struct DbConfig {
@@ -52,59 +282,107 @@ pub struct DbConfig {
     pub host: Option<String>,
     pub port: Option<String>,
     pub password: Option<String>,
+    pub file: Option<String>,
     pub real_time: bool,
 }
 
 impl<K, V> Cache<K, V>
 where
-    K: Sync + Send + Unpin + Serialize + DeserializeOwned,
-    V: Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed,
+    K: Sync + Send + Unpin + Serialize + DeserializeOwned + 'static,
+    V: Clone + Sync + Send + Unpin + Serialize + DeserializeOwned + HasElapsed + 'static,
 {
     pub fn new(conf: DbConfig) -> Self {
-        let collection = if let Some(name) = conf.name {
-            let host = conf.host.unwrap_or("localhost".to_string());
-            let port = conf.port.unwrap_or("27017".to_string()).parse().ok();
-            let addr = ServerAddress::Tcp { host, port };
-            let credential = conf.password.map(|password| {
-                Credential::builder()
-                    .username("admin".to_string())
-                    .password(password)
-                    .build()
-            });
-            let client_options = ClientOptions::builder()
-                .hosts(vec![addr])
-                .credential(credential)
-                .build();
-            let client = MongoClient::with_options(client_options).unwrap();
-            let db = client.database(&name);
-            Some(db.collection::<CacheData<K, V>>("cache"))
+        let backend: Option<Box<dyn CacheBackend<K, V>>> = if let Some(name) = conf.name {
+            Some(Box::new(MongoBackend::new(
+                &name,
+                conf.host,
+                conf.port,
+                conf.password,
+            )))
+        } else if let Some(file) = conf.file {
+            Some(Box::new(FileBackend::new(&file)))
         } else {
             None
         };
         Self {
-            collection,
+            backend,
             real_time: conf.real_time,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
         }
     }
 
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Acquire)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Acquire)
+    }
+
     pub async fn get(&self, key: &K) -> Option<V> {
-        let collection = self.collection.as_ref()?;
-        let data = collection
-            .find_one(doc! { "_id": mongodb::bson::to_bson(key).unwrap() }, None)
-            .await
-            .unwrap()?;
+        let backend = self.backend.as_ref()?;
+        let val = backend.get(key).await;
+        let counter = if val.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::AcqRel);
+        let val = val?;
         if self.real_time {
-            tokio::time::sleep(Duration::from_secs_f32(data.val.elapsed())).await;
+            tokio::time::sleep(Duration::from_secs_f32(val.elapsed())).await;
         }
-        Some(data.val)
+        Some(val)
     }
 
     pub async fn insert(&self, key: K, value: V) {
-        let collection = some_or!(self.collection.as_ref(), return);
-        let data = CacheData {
-            _id: key,
-            val: value,
-        };
-        let _ = collection.insert_one(data, None).await;
+        let backend = some_or!(self.backend.as_ref(), return);
+        backend.insert(key, value).await;
+    }
+
+    pub async fn entries(&self) -> Vec<(K, V)> {
+        let backend = some_or!(self.backend.as_ref(), return vec![]);
+        backend.entries().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Val(String);
+
+    impl HasElapsed for Val {
+        fn elapsed(&self) -> f32 {
+            0.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_backend() {
+        let path = TempPath::new("cache.jsonl");
+
+        let backend = FileBackend::<Val>::new(path.as_str());
+        let key = ("user".to_string(), "hello".to_string());
+        assert_eq!(CacheBackend::<_, Val>::get(&backend, &key).await, None);
+        backend.insert(key.clone(), Val("world".to_string())).await;
+        backend.insert(key.clone(), Val("again".to_string())).await;
+
+        let backend = FileBackend::<Val>::new(path.as_str());
+        assert_eq!(backend.get(&key).await, Some(Val("world".to_string())));
+    }
+
+    #[test]
+    fn test_tag_key() {
+        let key = serde_json::json!({ "messages": [["user", "hi"]], "stop": null });
+        let tagged = tag_key(&key, "gpt-4o", 1).unwrap();
+        assert_eq!(
+            tagged.to_string(),
+            r#"{"messages":[["user","hi"]],"model":"gpt-4o","stop":null,"version":1}"#
+        );
+        assert_eq!(tag_key(&tagged, "gpt-4o", 1), None);
     }
 }
@@ -15,30 +15,42 @@ use serde::{Deserialize, Serialize};
 
 use super::{
     cache::{Cache, DbConfig, HasElapsed},
-    tokens_in_str, LanguageModel,
+    tokens_in_str, LanguageModel, PROMPT_VERSION,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheKey {
-    prompt: String,
+    model: String,
     new_tokens_once: usize,
+    prompt: String,
     stop: Option<String>,
+    version: u32,
 }
 
 impl CacheKey {
-    fn new<S: AsRef<str>>(prompt: &str, new_tokens_once: usize, stop: &Option<S>) -> Self {
+    fn new<S: AsRef<str>>(
+        model: &str,
+        prompt: &str,
+        new_tokens_once: usize,
+        stop: &Option<S>,
+    ) -> Self {
+        let model = model.to_string();
         let prompt = prompt.to_string();
         let stop = stop.as_ref().map(|s| s.as_ref().to_string());
         Self {
-            prompt,
+            model,
             new_tokens_once,
+            prompt,
             stop,
+            version: PROMPT_VERSION,
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheVal {
+    #[serde(default)]
+    kind: String,
     content: String,
     response_tokens: usize,
     request_tokens: usize,
@@ -46,8 +58,15 @@ struct CacheVal {
 }
 
 impl CacheVal {
-    fn new(content: String, response_tokens: usize, request_tokens: usize, elapsed: f32) -> Self {
+    fn new(
+        kind: &str,
+        content: String,
+        response_tokens: usize,
+        request_tokens: usize,
+        elapsed: f32,
+    ) -> Self {
         Self {
+            kind: kind.to_string(),
             content,
             response_tokens,
             request_tokens,
@@ -70,6 +89,7 @@ struct GenerationResult {
 pub struct LocalClient {
     inner: Client,
     url: String,
+    model: Option<String>,
     cache: Cache<CacheKey, CacheVal>,
     can_send_request: AtomicBool,
 
@@ -79,12 +99,13 @@ pub struct LocalClient {
 }
 
 impl LocalClient {
-    pub fn new(url: String, db_conf: DbConfig) -> Self {
+    pub fn new(url: String, model: Option<String>, db_conf: DbConfig) -> Self {
         let inner = Client::new();
         let cache = Cache::new(db_conf);
         Self {
             inner,
             url,
+            model,
             cache,
             can_send_request: AtomicBool::new(true),
             total_request_tokens: AtomicUsize::new(0),
@@ -95,6 +116,7 @@ impl LocalClient {
 
     async fn send_request(
         &self,
+        kind: &str,
         prompt: &str,
         new_tokens_once: usize,
         stop: Option<&str>,
@@ -103,7 +125,8 @@ impl LocalClient {
             panic!("{}", prompt);
         }
 
-        let key = CacheKey::new(prompt, new_tokens_once, &stop);
+        let model = self.model.as_deref().unwrap_or_default();
+        let key = CacheKey::new(model, prompt, new_tokens_once, &stop);
         let (result, hit) = if let Some(result) = self.cache.get(&key).await {
             (result, true)
         } else {
@@ -128,7 +151,7 @@ impl LocalClient {
 
             let request_tokens = tokens_in_str(prompt);
             let response_tokens = tokens_in_str(&response);
-            let val = CacheVal::new(response, request_tokens, response_tokens, elapsed);
+            let val = CacheVal::new(kind, response, request_tokens, response_tokens, elapsed);
 
             self.cache.insert(key, val.clone()).await;
             (val, false)
@@ -173,6 +196,14 @@ impl LanguageModel for LocalClient {
         *self.total_response_time.lock().unwrap()
     }
 
+    fn cache_hits(&self) -> usize {
+        self.cache.hits()
+    }
+
+    fn cache_misses(&self) -> usize {
+        self.cache.misses()
+    }
+
     async fn rename_type(&self, name: &str) -> String {
         if name.chars().next().unwrap().is_uppercase()
             && !name.contains('_')
@@ -194,7 +225,7 @@ impl LanguageModel for LocalClient {
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(name) * 2, Some("`"))
+            .send_request("rename_type", &prompt, tokens_in_str(name) * 2, Some("`"))
             .await;
         let i = res.find('`').unwrap();
         res[..i].to_string()
@@ -250,7 +281,12 @@ Try to avoid unsafe code.",
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(code) * 2, Some("```"))
+            .send_request(
+                "translate_type",
+                &prompt,
+                tokens_in_str(code) * 2,
+                Some("```"),
+            )
             .await;
         let i = res.find("```")?;
         Some(res[..i].to_string())
@@ -274,7 +310,12 @@ Try to avoid unsafe code.",
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(name) * 2, Some("`"))
+            .send_request(
+                "rename_variable",
+                &prompt,
+                tokens_in_str(name) * 2,
+                Some("`"),
+            )
             .await;
         let i = res.find('`').unwrap();
         res[..i].to_string()
@@ -316,7 +357,60 @@ Try to avoid unsafe code.",
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(code) * 2, Some("```"))
+            .send_request(
+                "translate_variable",
+                &prompt,
+                tokens_in_str(code) * 2,
+                Some("```"),
+            )
+            .await;
+        let i = res.find("```")?;
+        Some(res[..i].to_string())
+    }
+
+    async fn translate_macro(&self, code: &str, deps: &[String]) -> Option<String> {
+        fn task(code: &str, deps: &[String]) -> String {
+            format!(
+                "{}Translate the following C macro definition to Rust:
+```c
+{}
+```
+If the macro stands for a constant, write a `const` item. Otherwise, write an `#[inline]` function if possible, and a `macro_rules!` macro if not.
+Try to avoid unsafe code.",
+                make_deps(deps),
+                code
+            )
+        }
+        fn answer(code: &str, close: bool) -> String {
+            format!(
+                "This is the equivalent Rust definition:
+```rust
+{}{}",
+                code,
+                if close { "\n```" } else { "" }
+            )
+        }
+        let prompt = make_prompt(
+            Some(HEADER),
+            &[
+                (
+                    task("#define MAX_LEN 256", &[]),
+                    answer("const MAX_LEN: usize = 256;", true),
+                ),
+                (
+                    task("#define square(x) ((x) * (x))", &[]),
+                    answer("#[inline]\nfn square(x: i32) -> i32 {\n    x * x\n}", true),
+                ),
+                (task(code, deps), answer("", false)),
+            ],
+        );
+        let res = self
+            .send_request(
+                "translate_macro",
+                &prompt,
+                tokens_in_str(code) * 4,
+                Some("```"),
+            )
             .await;
         let i = res.find("```")?;
         Some(res[..i].to_string())
@@ -340,7 +434,12 @@ Try to avoid unsafe code.",
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(name) * 2, Some("`"))
+            .send_request(
+                "rename_function",
+                &prompt,
+                tokens_in_str(name) * 2,
+                Some("`"),
+            )
             .await;
         let i = res.find('`').unwrap();
         res[..i].to_string()
@@ -423,7 +522,9 @@ Give {} Rust-idiomatic candidate signatures.",
                     (task(code, deps, n), answer(sigs_str.as_str())),
                 ],
             );
-            let res = self.send_request(&prompt, 32, Some("`")).await;
+            let res = self
+                .send_request("translate_signature", &prompt, 32, Some("`"))
+                .await;
             let i = res.find('`').unwrap();
             let sig = res[..i].to_string();
             if let Some(sig) = sig.strip_suffix(';') {
@@ -509,7 +610,12 @@ Try to avoid unsafe code.",
             ],
         );
         let res = self
-            .send_request(&prompt, tokens_in_str(code) * 2, Some("```"))
+            .send_request(
+                "translate_function",
+                &prompt,
+                tokens_in_str(code) * 2,
+                Some("```"),
+            )
             .await;
         let i = res.find("```")?;
         start += &res[..i];
@@ -541,7 +647,62 @@ Write the fixed code.",
         );
         let prompt = make_prompt(Some(HEADER), &[(task, answer)]);
         let res = self
-            .send_request(&prompt, tokens_in_str(code) * 2, Some("```"))
+            .send_request("fix", &prompt, tokens_in_str(code) * 2, Some("```"))
+            .await;
+        let i = res.find("```")?;
+        start += &res[..i];
+        Some(start)
+    }
+
+    async fn remove_unsafe(&self, code: &str, deps: &[String]) -> Option<String> {
+        fn task(code: &str, deps: &[String]) -> String {
+            format!(
+                "{}The following Rust function uses unsafe code:
+```rust
+{}
+```
+Rewrite it with safe equivalents of its unsafe code where possible, without changing its signature.",
+                make_deps(deps),
+                code
+            )
+        }
+        fn answer(code: &str, close: bool) -> String {
+            format!(
+                "This is the function without unsafe code:
+```rust
+{}{}",
+                code,
+                if close { "\n```" } else { "" }
+            )
+        }
+        let mut start = code
+            .lines()
+            .find(|line| !line.starts_with("///"))
+            .and_then(|line| line.find('{').map(|i| line[..=i].to_string()))
+            .unwrap_or_default();
+        let prompt = make_prompt(
+            Some(HEADER),
+            &[
+                (
+                    task(
+                        "fn sum(v: &[i32], n: usize) -> i32 {\n    let mut s = 0;\n    for i in 0..n {\n        s += unsafe { *v.as_ptr().add(i) };\n    }\n    s\n}",
+                        &[],
+                    ),
+                    answer(
+                        "fn sum(v: &[i32], n: usize) -> i32 {\n    v[..n].iter().sum()\n}",
+                        true,
+                    ),
+                ),
+                (task(code, deps), answer(&start, false)),
+            ],
+        );
+        let res = self
+            .send_request(
+                "remove_unsafe",
+                &prompt,
+                tokens_in_str(code) * 2,
+                Some("```"),
+            )
             .await;
         let i = res.find("```")?;
         start += &res[..i];
@@ -595,7 +756,7 @@ Which one is more Rust-idiomatic?",
                 (task(code1, code2), answer("", false)),
             ],
         );
-        let res = self.send_request(&prompt, 2, Some("`")).await;
+        let res = self.send_request("compare", &prompt, 2, Some("`")).await;
         let c = some_or!(
             res.chars().find(|&c| c == '1' || c == '2'),
             return std::cmp::Ordering::Equal
@@ -667,3 +828,19 @@ Each signature must look like `fn {5}(...);` or `fn {5}(...) -> ...;`.",
         new_name
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{super::cache::check_tag_key, *};
+
+    #[test]
+    fn test_cache_key() {
+        check_tag_key(&CacheKey {
+            model: "llama".to_string(),
+            new_tokens_once: 100,
+            prompt: "hi".to_string(),
+            stop: None,
+            version: PROMPT_VERSION,
+        });
+    }
+}
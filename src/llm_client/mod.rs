@@ -1,14 +1,20 @@
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 
+pub mod anthropic;
 pub mod cache;
 pub mod local;
 pub mod openai;
+pub mod replay;
 
 lazy_static! {
     static ref BPE: tiktoken_rs::CoreBPE = tiktoken_rs::cl100k_base().unwrap();
 }
 
+/// Part of every cache key. Bump it whenever a prompt changes so that stale responses are not
+/// reused.
+pub const PROMPT_VERSION: u32 = 1;
+
 pub fn tokens_in_str(s: &str) -> usize {
     BPE.encode_with_special_tokens(s).len()
 }
@@ -18,11 +24,14 @@ pub trait LanguageModel {
     fn request_tokens(&self) -> usize;
     fn response_tokens(&self) -> usize;
     fn response_time(&self) -> f32;
+    fn cache_hits(&self) -> usize;
+    fn cache_misses(&self) -> usize;
 
     async fn rename_type(&self, name: &str) -> String;
     async fn translate_type(&self, code: &str, sort: &str, deps: &[String]) -> Option<String>;
     async fn rename_variable(&self, name: &str) -> String;
     async fn translate_variable(&self, code: &str, deps: &[String]) -> Option<String>;
+    async fn translate_macro(&self, code: &str, deps: &[String]) -> Option<String>;
     async fn rename_function(&self, name: &str) -> String;
     async fn translate_signature(
         &self,
@@ -38,5 +47,6 @@ pub trait LanguageModel {
         deps: &[String],
     ) -> Option<String>;
     async fn fix(&self, code: &str, error: &str) -> Option<String>;
+    async fn remove_unsafe(&self, code: &str, deps: &[String]) -> Option<String>;
     async fn compare(&self, code1: &str, code2: &str) -> std::cmp::Ordering;
 }
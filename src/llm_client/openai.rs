@@ -14,30 +14,44 @@ use serde::{Deserialize, Serialize};
 
 use super::{
     cache::{Cache, DbConfig, HasElapsed},
-    tokens_in_str, LanguageModel,
+    tokens_in_str, LanguageModel, PROMPT_VERSION,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheKey {
     messages: Vec<(String, String)>,
+    model: String,
     stop: Option<String>,
+    version: u32,
 }
 
 impl CacheKey {
-    fn new<S: AsRef<str>>(messages: &[ChatCompletionRequestMessage], stop: &Option<S>) -> Self {
+    fn new<S: AsRef<str>>(
+        model: &str,
+        messages: &[ChatCompletionRequestMessage],
+        stop: &Option<S>,
+    ) -> Self {
         let messages = messages
             .iter()
             .map(|ChatCompletionRequestMessage { role, content, .. }| {
                 (role_to_str(role).to_string(), content.clone())
             })
             .collect();
+        let model = model.to_string();
         let stop = stop.as_ref().map(|s| s.as_ref().to_string());
-        Self { messages, stop }
+        Self {
+            messages,
+            model,
+            stop,
+            version: PROMPT_VERSION,
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct CacheVal {
+    #[serde(default)]
+    kind: String,
     content: String,
     reason: Option<String>,
     response_tokens: usize,
@@ -47,6 +61,7 @@ struct CacheVal {
 
 impl CacheVal {
     fn new(
+        kind: &str,
         content: String,
         reason: Option<String>,
         response_tokens: usize,
@@ -54,6 +69,7 @@ impl CacheVal {
         elapsed: f32,
     ) -> Self {
         Self {
+            kind: kind.to_string(),
             content,
             reason,
             response_tokens,
@@ -87,10 +103,19 @@ pub struct OpenAIClient {
 }
 
 impl OpenAIClient {
-    pub fn new(model: Option<String>, api_key_file: Option<String>, db_conf: DbConfig) -> Self {
+    pub fn new(
+        model: Option<String>,
+        api_key_file: Option<String>,
+        url: Option<String>,
+        db_conf: DbConfig,
+    ) -> Self {
         let inner = api_key_file.map(|api_key_file| {
             let api_key = fs::read_to_string(api_key_file).unwrap().trim().to_string();
-            Client::new().with_api_key(api_key)
+            let client = Client::new().with_api_key(api_key);
+            match url {
+                Some(url) => client.with_api_base(url),
+                None => client,
+            }
         });
         let cache = Cache::new(db_conf);
         Self {
@@ -106,6 +131,7 @@ impl OpenAIClient {
 
     async fn send_request(
         &self,
+        kind: &str,
         mut msgs: Vec<ChatCompletionRequestMessage>,
         stop: Option<&str>,
     ) -> String {
@@ -115,7 +141,8 @@ impl OpenAIClient {
             .collect::<Vec<_>>()
             .join("\n");
 
-        let key = CacheKey::new(&msgs, &stop);
+        let model = self.model.as_deref().unwrap_or_default();
+        let key = CacheKey::new(model, &msgs, &stop);
         let (result, hit) = if let Some(result) = self.cache.get(&key).await {
             (result, true)
         } else {
@@ -219,6 +246,7 @@ impl OpenAIClient {
             let request_tokens = usage.prompt_tokens;
             let response_tokens = usage.completion_tokens;
             let val = CacheVal::new(
+                kind,
                 content,
                 reason,
                 request_tokens as _,
@@ -269,6 +297,14 @@ impl LanguageModel for OpenAIClient {
         *self.total_response_time.lock().unwrap()
     }
 
+    fn cache_hits(&self) -> usize {
+        self.cache.hits()
+    }
+
+    fn cache_misses(&self) -> usize {
+        self.cache.misses()
+    }
+
     async fn rename_type(&self, name: &str) -> String {
         if name.chars().next().unwrap().is_uppercase()
             && !name.contains('_')
@@ -288,7 +324,7 @@ impl LanguageModel for OpenAIClient {
         let prompt = format!("Convert `{}` to `CamelCase`.", name);
         let m10 = user(&prompt);
         let msgs = vec![m1, m2, m3, m4, m5, m6, m7, m8, m9, m10];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("rename_type", msgs, None).await;
         extract_name(result)
     }
 
@@ -305,7 +341,7 @@ Try to avoid unsafe code.",
         );
         let m2 = user(&prompt);
         let msgs = vec![m1, m2];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("translate_type", msgs, None).await;
         extract_code(&result, &["type ", "struct ", "union ", "enum "])
     }
 
@@ -325,7 +361,7 @@ Try to avoid unsafe code.",
         let prompt = format!("Convert `{}` to `SCREAMING_SNAKE_CASE`.", name);
         let m10 = user(&prompt);
         let msgs = vec![m1, m2, m3, m4, m5, m6, m7, m8, m9, m10];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("rename_variable", msgs, None).await;
         extract_name(result).replace(' ', "_")
     }
 
@@ -342,10 +378,38 @@ Try to avoid unsafe code.",
         );
         let m2 = user(&prompt);
         let msgs = vec![m1, m2];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("translate_variable", msgs, None).await;
         extract_code(&result, &["const ", "static "])
     }
 
+    async fn translate_macro(&self, code: &str, deps: &[String]) -> Option<String> {
+        let m1 = system("You are a helpful assistant that translates C to Rust.");
+        let deps = make_deps(deps);
+        let prompt = format!(
+            "{}Translate the following C macro definition to Rust without any explanation:
+```
+{}
+```
+If the macro stands for a constant, write a `const` item. Otherwise, write an `#[inline]` function if possible, and a `macro_rules!` macro if not.
+Try to avoid unsafe code.",
+            deps, code
+        );
+        let m2 = user(&prompt);
+        let msgs = vec![m1, m2];
+        let result = self.send_request("translate_macro", msgs, None).await;
+        extract_code(
+            &result,
+            &[
+                "const ",
+                "pub const ",
+                "#[inline]",
+                "fn ",
+                "pub fn ",
+                "macro_rules!",
+            ],
+        )
+    }
+
     async fn rename_function(&self, name: &str) -> String {
         if !name.contains(|c: char| c.is_uppercase()) {
             return name.to_string();
@@ -362,7 +426,7 @@ Try to avoid unsafe code.",
         let prompt = format!("Convert `{}` to `snake_case`.", name);
         let m10 = user(&prompt);
         let msgs = vec![m1, m2, m3, m4, m5, m6, m7, m8, m9, m10];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("rename_function", msgs, None).await;
         extract_name(result)
     }
 
@@ -423,7 +487,7 @@ Signatures:
         );
         let m6 = user(&signature_prompt(code, new_name, deps, n));
         let msgs = vec![m1, m2, m3, m4, m5, m6];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("translate_signature", msgs, None).await;
         let sigs: Vec<_> = result
             .lines()
             .filter_map(|s| {
@@ -506,7 +570,7 @@ Signatures:
         );
         let m2 = user(&prompt);
         let msgs = vec![m1, m2];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("translate_function", msgs, None).await;
         let result = extract_code(&result, &["fn ", "unsafe fn "]).or_else(|| {
             if result.starts_with("fn ") | result.starts_with("unsafe fn ") {
                 Some(result)
@@ -549,7 +613,7 @@ The error message is:
         );
         let m2 = user(&prompt);
         let msgs = vec![m1, m2];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("fix", msgs, None).await;
         extract_code(
             &result,
             &[
@@ -558,6 +622,24 @@ The error message is:
         )
     }
 
+    async fn remove_unsafe(&self, code: &str, deps: &[String]) -> Option<String> {
+        let m1 = system("You are a helpful assistant that writes safe Rust code.");
+        let deps = make_deps(deps);
+        let prompt = format!(
+            "{}The following Rust function uses unsafe code:
+```
+{}
+```
+Rewrite the function without any explanation, replacing unsafe blocks, raw pointer dereferences and accesses to mutable static variables with safe equivalents where possible.
+Do not change the signature of the function.",
+            deps, code
+        );
+        let m2 = user(&prompt);
+        let msgs = vec![m1, m2];
+        let result = self.send_request("remove_unsafe", msgs, None).await;
+        extract_code(&result, &["fn ", "pub fn ", "unsafe fn ", "pub unsafe fn "])
+    }
+
     async fn compare(&self, code1: &str, code2: &str) -> std::cmp::Ordering {
         if tokens_in_str(code1) + tokens_in_str(code2) > 3820 {
             return std::cmp::Ordering::Equal;
@@ -613,7 +695,7 @@ Choice: Implementation [n]",
         );
         let m4 = user(&prompt);
         let msgs = vec![m1, m2, m3, m4];
-        let result = self.send_request(msgs, None).await;
+        let result = self.send_request("compare", msgs, None).await;
         let s = "Choice: Implementation ";
         let i = some_or!(result.find(s), return std::cmp::Ordering::Equal);
         let c = some_or!(
@@ -748,3 +830,18 @@ fn assistant(s: &str) -> ChatCompletionRequestMessage {
         name: None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{super::cache::check_tag_key, *};
+
+    #[test]
+    fn test_cache_key() {
+        check_tag_key(&CacheKey {
+            messages: vec![("user".to_string(), "hi".to_string())],
+            model: "gpt-4o".to_string(),
+            stop: Some("\n".to_string()),
+            version: PROMPT_VERSION,
+        });
+    }
+}
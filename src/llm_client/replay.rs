@@ -0,0 +1,384 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::LanguageModel;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum CacheKey {
+    RenameType {
+        name: String,
+    },
+    TranslateType {
+        code: String,
+        sort: String,
+        deps: Vec<String>,
+    },
+    RenameVariable {
+        name: String,
+    },
+    TranslateVariable {
+        code: String,
+        deps: Vec<String>,
+    },
+    TranslateMacro {
+        code: String,
+        deps: Vec<String>,
+    },
+    RenameFunction {
+        name: String,
+    },
+    TranslateSignature {
+        code: String,
+        new_name: String,
+        deps: Vec<String>,
+        n: usize,
+    },
+    TranslateFunction {
+        code: String,
+        signature: Option<String>,
+        deps: Vec<String>,
+    },
+    Fix {
+        code: String,
+        error: String,
+    },
+    RemoveUnsafe {
+        code: String,
+        deps: Vec<String>,
+    },
+    Compare {
+        code1: String,
+        code2: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: CacheKey,
+    val: serde_json::Value,
+}
+
+/// Records every request made to a language model into a transcript file, or answers requests
+/// from a previously recorded transcript without touching the network or the cache.
+pub struct ReplayClient {
+    inner: Option<Box<dyn LanguageModel + Send + Sync>>,
+    writer: Option<Mutex<File>>,
+    recorded: HashMap<String, serde_json::Value>,
+}
+
+impl ReplayClient {
+    pub fn record(inner: Box<dyn LanguageModel + Send + Sync>, path: &str) -> Self {
+        let file = File::create(path).unwrap();
+        Self {
+            inner: Some(inner),
+            writer: Some(Mutex::new(file)),
+            recorded: HashMap::new(),
+        }
+    }
+
+    pub fn replay(path: &str) -> Self {
+        let file = File::open(path).unwrap();
+        let mut recorded = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.unwrap();
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).unwrap();
+            let key = serde_json::to_string(&entry.key).unwrap();
+            recorded.entry(key).or_insert(entry.val);
+        }
+        Self {
+            inner: None,
+            writer: None,
+            recorded,
+        }
+    }
+
+    fn inner(&self) -> Option<&(dyn LanguageModel + Send + Sync)> {
+        self.inner.as_deref()
+    }
+
+    fn write<T: Serialize>(&self, key: CacheKey, val: &T) {
+        let entry = Entry {
+            key,
+            val: serde_json::to_value(val).unwrap(),
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        let mut writer = self.writer.as_ref().unwrap().lock().unwrap();
+        writeln!(writer, "{}", line).unwrap();
+    }
+
+    fn read<T: DeserializeOwned>(&self, key: &CacheKey) -> T {
+        let s = serde_json::to_string(key).unwrap();
+        let val = self
+            .recorded
+            .get(&s)
+            .unwrap_or_else(|| panic!("request missing from the transcript:\n{:#?}", key));
+        serde_json::from_value(val.clone()).unwrap()
+    }
+}
+
+#[async_trait]
+impl LanguageModel for ReplayClient {
+    fn request_tokens(&self) -> usize {
+        self.inner()
+            .map(|inner| inner.request_tokens())
+            .unwrap_or(0)
+    }
+
+    fn response_tokens(&self) -> usize {
+        self.inner()
+            .map(|inner| inner.response_tokens())
+            .unwrap_or(0)
+    }
+
+    fn response_time(&self) -> f32 {
+        self.inner()
+            .map(|inner| inner.response_time())
+            .unwrap_or(0.0)
+    }
+
+    fn cache_hits(&self) -> usize {
+        self.inner().map(|inner| inner.cache_hits()).unwrap_or(0)
+    }
+
+    fn cache_misses(&self) -> usize {
+        self.inner().map(|inner| inner.cache_misses()).unwrap_or(0)
+    }
+
+    async fn rename_type(&self, name: &str) -> String {
+        let key = CacheKey::RenameType {
+            name: name.to_string(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.rename_type(name).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn translate_type(&self, code: &str, sort: &str, deps: &[String]) -> Option<String> {
+        let key = CacheKey::TranslateType {
+            code: code.to_string(),
+            sort: sort.to_string(),
+            deps: deps.to_vec(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.translate_type(code, sort, deps).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn rename_variable(&self, name: &str) -> String {
+        let key = CacheKey::RenameVariable {
+            name: name.to_string(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.rename_variable(name).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn translate_variable(&self, code: &str, deps: &[String]) -> Option<String> {
+        let key = CacheKey::TranslateVariable {
+            code: code.to_string(),
+            deps: deps.to_vec(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.translate_variable(code, deps).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn translate_macro(&self, code: &str, deps: &[String]) -> Option<String> {
+        let key = CacheKey::TranslateMacro {
+            code: code.to_string(),
+            deps: deps.to_vec(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.translate_macro(code, deps).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn rename_function(&self, name: &str) -> String {
+        let key = CacheKey::RenameFunction {
+            name: name.to_string(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.rename_function(name).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn translate_signature(
+        &self,
+        code: &str,
+        new_name: &str,
+        deps: &[String],
+        n: usize,
+    ) -> Vec<String> {
+        let key = CacheKey::TranslateSignature {
+            code: code.to_string(),
+            new_name: new_name.to_string(),
+            deps: deps.to_vec(),
+            n,
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.translate_signature(code, new_name, deps, n).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn translate_function(
+        &self,
+        code: &str,
+        signature: Option<&str>,
+        deps: &[String],
+    ) -> Option<String> {
+        let key = CacheKey::TranslateFunction {
+            code: code.to_string(),
+            signature: signature.map(|s| s.to_string()),
+            deps: deps.to_vec(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.translate_function(code, signature, deps).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn fix(&self, code: &str, error: &str) -> Option<String> {
+        let key = CacheKey::Fix {
+            code: code.to_string(),
+            error: error.to_string(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.fix(code, error).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn remove_unsafe(&self, code: &str, deps: &[String]) -> Option<String> {
+        let key = CacheKey::RemoveUnsafe {
+            code: code.to_string(),
+            deps: deps.to_vec(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.remove_unsafe(code, deps).await;
+            self.write(key, &res);
+            res
+        } else {
+            self.read(&key)
+        }
+    }
+
+    async fn compare(&self, code1: &str, code2: &str) -> Ordering {
+        let key = CacheKey::Compare {
+            code1: code1.to_string(),
+            code2: code2.to_string(),
+        };
+        if let Some(inner) = self.inner() {
+            let res = inner.compare(code1, code2).await;
+            self.write(key, &(res as i8));
+            res
+        } else {
+            self.read::<i8>(&key).cmp(&0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockModel, TempPath};
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let path = TempPath::new("replay.jsonl");
+        let deps = ["int n;".to_string()];
+        let model = MockModel {
+            variable: Some("static X: i32 = 0;".to_string()),
+            ..Default::default()
+        };
+
+        let client = ReplayClient::record(Box::new(model), path.as_str());
+        assert_eq!(client.rename_type("point").await, "point");
+        assert_eq!(client.rename_variable("n").await, "N");
+        assert_eq!(
+            client.translate_variable("int x;", &deps).await.unwrap(),
+            "static X: i32 = 0;"
+        );
+        assert_eq!(
+            client.translate_function("int f();", None, &deps).await,
+            None
+        );
+        assert_eq!(
+            client.translate_signature("int f();", "f", &deps, 1).await,
+            ["fn f(x: i32) -> i32"]
+        );
+        assert_eq!(client.compare("a", "bb").await, Ordering::Equal);
+        drop(client);
+
+        let client = ReplayClient::replay(path.as_str());
+        assert_eq!(client.rename_type("point").await, "point");
+        assert_eq!(client.rename_variable("n").await, "N");
+        assert_eq!(
+            client.translate_variable("int x;", &deps).await.unwrap(),
+            "static X: i32 = 0;"
+        );
+        assert_eq!(
+            client.translate_function("int f();", None, &deps).await,
+            None
+        );
+        assert_eq!(
+            client.translate_signature("int f();", "f", &deps, 1).await,
+            ["fn f(x: i32) -> i32"]
+        );
+        assert_eq!(client.compare("a", "bb").await, Ordering::Equal);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "request missing from the transcript")]
+    async fn test_replay_missing() {
+        let path = TempPath::new("missing.jsonl");
+        std::fs::write(path.path(), "").unwrap();
+        let client = ReplayClient::replay(path.as_str());
+        client.rename_function("f").await;
+    }
+}
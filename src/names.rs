@@ -0,0 +1,231 @@
+use std::collections::BTreeSet;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::compiler;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameKind {
+    Type,
+    Variable,
+    Function,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RenameReason {
+    Invalid,
+    Keyword,
+    Std,
+    Collision,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rename {
+    pub c_name: String,
+    pub proposed: String,
+    pub new_name: String,
+    pub reason: RenameReason,
+}
+
+/// Rust names given so far. Types, variables and functions share one set, so that no two items
+/// of the translation have the same name.
+#[derive(Debug, Default)]
+pub struct Names {
+    used: BTreeSet<String>,
+    renames: Vec<Rename>,
+}
+
+impl Names {
+    /// Takes a name chosen by hand as it is. Returns false if it is already taken.
+    pub fn reserve(&mut self, name: &str) -> bool {
+        self.used.insert(name.to_string())
+    }
+
+    /// Turns the proposed name of a C item into a valid Rust name that is not reserved, does not
+    /// shadow `std` and is not taken yet, and takes it.
+    pub fn sanitize(&mut self, c_name: &str, proposed: &str, kind: NameKind) -> String {
+        let mut reason = None;
+        let mut name: String = proposed
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            name.insert(0, '_');
+        }
+        if name != proposed {
+            reason = Some(RenameReason::Invalid);
+        }
+
+        let shadows_std = match kind {
+            NameKind::Type => compiler::is_std_name(&name) || PRIMITIVES.contains(name.as_str()),
+            NameKind::Variable | NameKind::Function => compiler::is_prelude_name(&name),
+        };
+        // `main` is taken by the generated entry point.
+        let reserved = is_keyword(&name) || (kind != NameKind::Type && name == "main");
+        if reserved || shadows_std {
+            reason = Some(if shadows_std {
+                RenameReason::Std
+            } else {
+                RenameReason::Keyword
+            });
+            name = match kind {
+                NameKind::Type => {
+                    let mut chars = name.chars();
+                    let first = chars.next().unwrap().to_ascii_uppercase();
+                    format!("My{}{}", first, chars.as_str())
+                }
+                NameKind::Variable if is_upper(&name) => format!("MY_{}", name),
+                NameKind::Variable | NameKind::Function => format!("my_{}", name),
+            };
+        }
+
+        if self.used.contains(&name) {
+            reason = Some(RenameReason::Collision);
+            let sep = if kind == NameKind::Type { "" } else { "_" };
+            let base = name;
+            let mut i = 2;
+            name = format!("{}{}{}", base, sep, i);
+            while self.used.contains(&name) {
+                i += 1;
+                name = format!("{}{}{}", base, sep, i);
+            }
+        }
+
+        if let Some(reason) = reason {
+            self.renames.push(Rename {
+                c_name: c_name.to_string(),
+                proposed: proposed.to_string(),
+                new_name: name.clone(),
+                reason,
+            });
+        }
+        self.used.insert(name.clone());
+        name
+    }
+
+    pub fn renames(&self) -> &[Rename] {
+        &self.renames
+    }
+}
+
+/// Whether the name is a strict, reserved or weak Rust keyword.
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(name)
+}
+
+fn is_upper(name: &str) -> bool {
+    name.chars().all(|c| !c.is_ascii_lowercase())
+}
+
+lazy_static! {
+    static ref KEYWORDS: BTreeSet<&'static str> = KEYWORDS_RAW.iter().copied().collect();
+    static ref PRIMITIVES: BTreeSet<&'static str> = PRIMITIVES_RAW.iter().copied().collect();
+}
+
+static KEYWORDS_RAW: [&str; 54] = [
+    "as",
+    "async",
+    "await",
+    "break",
+    "const",
+    "continue",
+    "crate",
+    "dyn",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "for",
+    "if",
+    "impl",
+    "in",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "move",
+    "mut",
+    "pub",
+    "ref",
+    "return",
+    "self",
+    "Self",
+    "static",
+    "struct",
+    "super",
+    "trait",
+    "true",
+    "type",
+    "unsafe",
+    "use",
+    "where",
+    "while",
+    "abstract",
+    "become",
+    "box",
+    "do",
+    "final",
+    "macro",
+    "override",
+    "priv",
+    "try",
+    "typeof",
+    "unsized",
+    "virtual",
+    "yield",
+    "union",
+    "macro_rules",
+    "_",
+];
+
+static PRIMITIVES_RAW: [&str; 17] = [
+    "bool", "char", "str", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64",
+    "u128", "usize", "f32", "f64",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let mut names = Names::default();
+        assert_eq!(
+            names.sanitize("match", "match", NameKind::Function),
+            "my_match"
+        );
+        assert_eq!(
+            names.sanitize("main", "main", NameKind::Function),
+            "my_main"
+        );
+        assert_eq!(names.sanitize("box", "Box", NameKind::Type), "MyBox");
+        assert_eq!(names.sanitize("str", "str", NameKind::Type), "MyStr");
+        assert_eq!(
+            names.sanitize("drop", "drop", NameKind::Function),
+            "my_drop"
+        );
+        assert_eq!(names.sanitize("none", "NONE", NameKind::Variable), "NONE");
+        assert_eq!(
+            names.sanitize("some", "Some", NameKind::Variable),
+            "my_Some"
+        );
+        assert_eq!(
+            names.sanitize("count", "COUNT", NameKind::Variable),
+            "COUNT"
+        );
+        assert_eq!(
+            names.sanitize("count", "COUNT", NameKind::Variable),
+            "COUNT_2"
+        );
+        assert_eq!(names.sanitize("Point", "Point", NameKind::Type), "Point");
+        assert_eq!(
+            names.sanitize("point", "Point", NameKind::Function),
+            "Point_2"
+        );
+        assert_eq!(names.sanitize("x", "1st-x", NameKind::Function), "_1st_x");
+        assert_eq!(names.sanitize("y", "ok", NameKind::Function), "ok");
+        assert_eq!(names.renames().len(), 9);
+    }
+}
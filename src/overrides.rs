@@ -0,0 +1,178 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::compiler::{self, FunctionInfo, ItemSort, ParsedItem};
+
+/// Decisions made by hand that take precedence over the language model. Every map is keyed by C
+/// identifiers, or by `name@path` for statics defined in several files; type keys match
+/// typedefs, structs, unions and enums of that name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overrides {
+    /// Rust names of C types.
+    pub type_names: BTreeMap<String, String>,
+    /// Rust names of C variables and functions.
+    pub names: BTreeMap<String, String>,
+    /// Signatures of functions, e.g. `fn parse(s: &str) -> Option<i32>`.
+    pub signatures: BTreeMap<String, String>,
+    /// Hand-written translations of types, used as they are.
+    pub types: BTreeMap<String, String>,
+    /// Hand-written translations of variables, used as they are.
+    pub variables: BTreeMap<String, String>,
+    /// Hand-written translations of functions, used as they are.
+    pub functions: BTreeMap<String, String>,
+}
+
+impl Overrides {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let read = || {
+            let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let overrides: Self = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+            overrides.validate()?;
+            Ok(overrides)
+        };
+        read().map_err(|e: String| format!("{}: {}", path.display(), e))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let codes = [
+            ("types", &self.types, &self.type_names),
+            ("variables", &self.variables, &self.names),
+            ("functions", &self.functions, &self.names),
+        ];
+        for (section, codes, names) in codes {
+            for (key, code) in codes {
+                let item = first_item(code)
+                    .ok_or_else(|| format!("invalid code for `{}` in {}", key, section))?;
+                if section == "functions" && item.as_function().is_none() {
+                    return Err(format!("code for `{}` does not start with a function", key));
+                }
+                if names.get(key).map_or(false, |name| *name != item.name) {
+                    return Err(format!("`{}` is named differently in its code", key));
+                }
+            }
+        }
+        for (key, sig) in &self.signatures {
+            if compiler::parse_signature(&format!("{}{{todo!()}}", sig)).is_none() {
+                return Err(format!("invalid signature for `{}`", key));
+            }
+        }
+
+        let type_keys: BTreeSet<_> = self.type_names.keys().chain(self.types.keys()).collect();
+        let term_keys: BTreeSet<_> = self
+            .names
+            .keys()
+            .chain(self.variables.keys())
+            .chain(self.functions.keys())
+            .collect();
+        let type_names = type_keys.into_iter().map(|key| (key, self.type_name(key)));
+        let term_names = term_keys.into_iter().map(|key| (key, self.term_name(key)));
+        for names in [type_names.collect::<Vec<_>>(), term_names.collect()] {
+            let mut keys = BTreeMap::new();
+            for (key, name) in names {
+                if let Some(other) = keys.insert(name.unwrap(), key) {
+                    return Err(format!("`{}` and `{}` have the same name", other, key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The Rust name of a C type: the pinned one, or else the name of its hand-written item.
+    pub fn type_name(&self, name: &str) -> Option<String> {
+        self.type_names
+            .get(name)
+            .cloned()
+            .or_else(|| item_name(self.types.get(name)?))
+    }
+
+    /// The Rust name of a C variable or function, found like `type_name`.
+    pub fn term_name(&self, name: &str) -> Option<String> {
+        self.names.get(name).cloned().or_else(|| {
+            let code = self
+                .variables
+                .get(name)
+                .or_else(|| self.functions.get(name))?;
+            item_name(code)
+        })
+    }
+
+    /// The pinned signature of a C function, renamed to `new_name` so that the file need not
+    /// repeat generated names.
+    pub fn signature(&self, name: &str, new_name: &str) -> Option<FunctionInfo> {
+        let sig = self.signatures.get(name)?;
+        let code = compiler::rename_item(&format!("{}{{todo!()}}", sig), new_name)?;
+        let (_, info) = compiler::parse_signature(&code)?;
+        Some(info)
+    }
+}
+
+fn first_item(code: &str) -> Option<ParsedItem> {
+    compiler::parse(code)?
+        .into_iter()
+        .find(|item| !matches!(item.sort, ItemSort::Use))
+}
+
+fn item_name(code: &str) -> Option<String> {
+    Some(first_item(code)?.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() {
+        let overrides: Overrides = serde_json::from_str(
+            r#"{
+                "type_names": { "cmatrix": "Matrix" },
+                "signatures": { "parse": "fn p(s: &str) -> Option<i32>" },
+                "types": { "point_t": "use std::fmt; struct Point { x: i32 }" },
+                "functions": { "add": "fn sum(a: i32, b: i32) -> i32 { a + b }" }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(overrides.type_name("cmatrix").unwrap(), "Matrix");
+        assert_eq!(overrides.type_name("point_t").unwrap(), "Point");
+        assert_eq!(overrides.term_name("add").unwrap(), "sum");
+        assert!(overrides.term_name("parse").is_none());
+        assert_eq!(
+            overrides.signature("parse", "parse_num").unwrap().signature,
+            "fn parse_num(s: &str) -> Option<i32>"
+        );
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let error = |json: &str| {
+            let overrides: Overrides = serde_json::from_str(json).unwrap();
+            overrides.validate().unwrap_err()
+        };
+        assert_eq!(
+            error(r#"{ "functions": { "add": "fn add(" } }"#),
+            "invalid code for `add` in functions"
+        );
+        assert_eq!(
+            error(r#"{ "functions": { "add": "struct Add;" } }"#),
+            "code for `add` does not start with a function"
+        );
+        assert_eq!(
+            error(r#"{ "signatures": { "add": "fn add(a: i32" } }"#),
+            "invalid signature for `add`"
+        );
+        assert_eq!(
+            error(r#"{ "names": { "add": "sum", "plus": "sum" } }"#),
+            "`add` and `plus` have the same name"
+        );
+        assert_eq!(
+            error(r#"{ "names": { "add": "sum" }, "functions": { "add": "fn add() {}" } }"#),
+            "`add` is named differently in its code"
+        );
+    }
+}
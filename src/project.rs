@@ -0,0 +1,98 @@
+use std::{fs, io, path::Path};
+
+use etrace::some_or;
+
+static DEPS_MANIFEST: &str = include_str!("../deps_crate/Cargo.toml");
+
+/// The dependencies of `deps_crate`, against which every translation is type-checked.
+pub fn dependencies() -> Vec<(&'static str, &'static str)> {
+    let mut in_deps = false;
+    let mut deps = vec![];
+    for line in DEPS_MANIFEST.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_deps = line == "[dependencies]";
+            continue;
+        }
+        if !in_deps {
+            continue;
+        }
+        let (name, version) = some_or!(line.split_once('='), continue);
+        let version = version.trim().trim_matches('"');
+        deps.push((name.trim(), version));
+    }
+    deps
+}
+
+fn package_name(dir: &Path) -> String {
+    let name: String = dir
+        .canonicalize()
+        .ok()
+        .as_deref()
+        .unwrap_or(dir)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name
+    } else {
+        format!("translated_{}", name)
+    }
+}
+
+fn manifest(name: &str) -> String {
+    let deps: String = dependencies()
+        .into_iter()
+        .map(|(name, version)| format!("{} = \"{}\"\n", name, version))
+        .collect();
+    format!(
+        "[package]
+name = \"{}\"
+version = \"0.1.0\"
+edition = \"2021\"
+
+[dependencies]
+{}",
+        name, deps
+    )
+}
+
+/// Writes a Cargo package at `dir` whose `src` consists of `files`, given as paths relative to
+/// `src` and their contents.
+pub fn write_project<P: AsRef<Path>>(dir: P, files: &[(String, String)]) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let src = dir.join("src");
+    fs::create_dir_all(&src)?;
+    fs::write(dir.join("Cargo.toml"), manifest(&package_name(dir)))?;
+    fs::write(dir.join(".gitignore"), "/target\n")?;
+    for (path, code) in files {
+        let path = src.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, code)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependencies() {
+        let deps = dependencies();
+        let names: Vec<_> = deps.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["once_cell", "libc"]);
+        assert!(manifest("gzip").contains("libc = \"0.2.141\"\n"));
+    }
+}
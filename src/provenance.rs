@@ -0,0 +1,65 @@
+use std::{collections::BTreeMap, fs::File, io, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::compiler::Stage;
+
+/// How a translated item came to be: its C code, the context given to the language model, and
+/// every signature, fix and comparison tried on the way.
+#[derive(Debug, Default, Serialize)]
+pub struct Trail {
+    pub c_code: String,
+    pub translation_prefix: Vec<String>,
+    pub proposed_signatures: Vec<String>,
+    pub signatures: Vec<SignatureTrial>,
+    pub fixes: Vec<FixRound>,
+    pub tournament: Vec<Comparison>,
+    pub result: Option<String>,
+}
+
+/// A call of `try_signature`. `signature` is `None` when the model chose the signature itself, and
+/// `translated` is false when no candidate came out of it.
+#[derive(Debug, Serialize)]
+pub struct SignatureTrial {
+    pub signature: Option<String>,
+    pub translated: bool,
+    pub stage: Option<Stage>,
+    pub errors: Option<usize>,
+    pub code: Option<String>,
+}
+
+/// A fix request sent to the model and the type checking result of its answer, if it could be
+/// checked. Only one accepted fix is kept per round.
+#[derive(Debug, Serialize)]
+pub struct FixRound {
+    pub signature: Option<String>,
+    pub error: String,
+    pub accepted: bool,
+    pub stage: Option<Stage>,
+    pub errors: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub best: String,
+    pub candidate: String,
+    pub candidate_won: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Provenance {
+    trails: Mutex<BTreeMap<String, Trail>>,
+}
+
+impl Provenance {
+    pub fn record<F: FnOnce(&mut Trail)>(&self, key: &str, f: F) {
+        let mut trails = self.trails.lock().unwrap();
+        f(trails.entry(key.to_string()).or_default());
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, &*self.trails.lock().unwrap())?;
+        Ok(())
+    }
+}
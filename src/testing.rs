@@ -0,0 +1,132 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+
+use crate::llm_client::LanguageModel;
+
+/// A path in the temporary directory, removed with its contents when dropped.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        let name = format!("simcrat-{}-{}", std::process::id(), name);
+        Self(std::env::temp_dir().join(name))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
+
+/// Gives fixed answers, or panics on any request if `offline` is set.
+#[derive(Default)]
+pub(crate) struct MockModel {
+    pub(crate) variable: Option<String>,
+    pub(crate) function: Option<String>,
+    pub(crate) offline: bool,
+}
+
+impl MockModel {
+    pub(crate) fn offline() -> Self {
+        Self {
+            offline: true,
+            ..Default::default()
+        }
+    }
+
+    fn answer<T>(&self, answer: T) -> T {
+        assert!(!self.offline, "unexpected request");
+        answer
+    }
+}
+
+#[async_trait]
+impl LanguageModel for MockModel {
+    fn request_tokens(&self) -> usize {
+        0
+    }
+
+    fn response_tokens(&self) -> usize {
+        0
+    }
+
+    fn response_time(&self) -> f32 {
+        0.0
+    }
+
+    fn cache_hits(&self) -> usize {
+        0
+    }
+
+    fn cache_misses(&self) -> usize {
+        0
+    }
+
+    async fn rename_type(&self, name: &str) -> String {
+        self.answer(name.to_string())
+    }
+
+    async fn translate_type(&self, _: &str, _: &str, _: &[String]) -> Option<String> {
+        self.answer(None)
+    }
+
+    async fn rename_variable(&self, name: &str) -> String {
+        self.answer(name.to_uppercase())
+    }
+
+    async fn translate_variable(&self, _: &str, _: &[String]) -> Option<String> {
+        self.answer(self.variable.clone())
+    }
+
+    async fn translate_macro(&self, _: &str, _: &[String]) -> Option<String> {
+        self.answer(None)
+    }
+
+    async fn rename_function(&self, name: &str) -> String {
+        self.answer(name.to_string())
+    }
+
+    async fn translate_signature(
+        &self,
+        _: &str,
+        new_name: &str,
+        _: &[String],
+        _: usize,
+    ) -> Vec<String> {
+        self.answer(vec![format!("fn {}(x: i32) -> i32", new_name)])
+    }
+
+    async fn translate_function(&self, _: &str, _: Option<&str>, _: &[String]) -> Option<String> {
+        self.answer(self.function.clone())
+    }
+
+    async fn fix(&self, _: &str, _: &str) -> Option<String> {
+        self.answer(None)
+    }
+
+    async fn remove_unsafe(&self, _: &str, _: &[String]) -> Option<String> {
+        self.answer(None)
+    }
+
+    async fn compare(&self, _: &str, _: &str) -> Ordering {
+        self.answer(Ordering::Equal)
+    }
+}
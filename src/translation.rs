@@ -1,25 +1,31 @@
 use std::{
+    cmp::Reverse,
     collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
     sync::RwLock,
 };
 
 use etrace::some_or;
 use futures::{future, FutureExt};
-use lang_c::{
-    ast::Identifier,
-    span::{Node, Span},
-};
-use lazy_static::lazy_static;
+use lang_c::span::Span;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     c_parser::{
-        self, CustomType, Enum, Function, Program, Struct, TypeDependency, TypeSort, Typedef,
-        Variable,
+        self, CustomType, Enum, Function, Macro, Program, Reference, Struct, TypeDependency,
+        TypeSort, Typedef, Variable,
+    },
+    compiler::{
+        self, FunTySig, FunctionInfo, ItemSort, ParsedItem, Stage, TypeCheckingResult, UnsafeCount,
     },
-    compiler::{self, FunTySig, FunctionInfo, ItemSort, ParsedItem, TypeCheckingResult},
+    differential::{self, Scalar},
     graph,
     graph::Id,
     llm_client::{tokens_in_str, LanguageModel},
+    names::{self, NameKind, Names, Rename},
+    overrides::Overrides,
+    provenance::{Comparison, FixRound, Provenance, SignatureTrial, Trail},
 };
 
 #[derive(Clone, Copy, Debug)]
@@ -41,6 +47,7 @@ pub struct Translator<'ast> {
     variables: BTreeMap<&'ast str, Variable<'ast>>,
     protos: BTreeMap<&'ast str, Variable<'ast>>,
     functions: BTreeMap<&'ast str, Function<'ast>>,
+    macros: BTreeMap<&'ast str, &'ast Macro>,
 
     custom_types: Vec<CustomType<'ast>>,
     transitive_types: BTreeMap<CustomType<'ast>, BTreeSet<CustomType<'ast>>>,
@@ -48,6 +55,7 @@ pub struct Translator<'ast> {
     transitive_functions: BTreeMap<&'ast str, BTreeSet<&'ast str>>,
     term_types: BTreeMap<&'ast str, BTreeSet<CustomType<'ast>>>,
     function_variables: BTreeMap<&'ast str, BTreeSet<&'ast str>>,
+    term_macros: BTreeMap<&'ast str, BTreeSet<&'ast str>>,
 
     type_graph: BTreeMap<Id, BTreeSet<Id>>,
     type_elem_map: BTreeMap<Id, BTreeSet<CustomType<'ast>>>,
@@ -61,10 +69,16 @@ pub struct Translator<'ast> {
 
     new_type_names: BTreeMap<CustomType<'ast>, String>,
     new_term_names: BTreeMap<&'ast str, String>,
+    new_macro_names: BTreeMap<&'ast str, String>,
 
     inner: RwLock<TranslatorInner<'ast>>,
 
     config: Config,
+    checkpoint: Option<String>,
+    resumed: bool,
+    provenance: Option<Provenance>,
+    overrides: Overrides,
+    renames: Vec<Rename>,
 }
 
 #[derive(Default)]
@@ -73,8 +87,13 @@ pub struct TranslatorInner<'ast> {
     translated_term_names: BTreeSet<String>,
 
     translated_types: BTreeMap<CustomType<'ast>, TranslationResult>,
+    translated_macros: BTreeMap<&'ast str, TranslationResult>,
     translated_variables: BTreeMap<&'ast str, TranslationResult>,
     translated_functions: BTreeMap<&'ast str, TranslationResult>,
+    finished_functions: BTreeSet<&'ast str>,
+    mismatches: BTreeMap<&'ast str, String>,
+    missing_functions: BTreeMap<&'ast str, Vec<String>>,
+    unsafe_counts: BTreeMap<&'ast str, UnsafeReduction>,
 }
 
 impl<'ast> TranslatorInner<'ast> {
@@ -100,12 +119,14 @@ impl<'ast> TranslatorInner<'ast> {
     fn collect_dependencies(
         &self,
         types: &[CustomType<'ast>],
+        macros: &[&'ast str],
         vars: &[&'ast str],
         funcs: &[&'ast str],
     ) -> Vec<&ParsedItem> {
         types
             .iter()
             .flat_map(|x| self.translated_types.get(x))
+            .chain(macros.iter().flat_map(|x| self.translated_macros.get(x)))
             .chain(vars.iter().flat_map(|x| self.translated_variables.get(x)))
             .chain(funcs.iter().flat_map(|x| self.translated_functions.get(x)))
             .flat_map(|x| &x.items)
@@ -113,15 +134,17 @@ impl<'ast> TranslatorInner<'ast> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct TranslationResult {
     items: Vec<ParsedItem>,
-    stage: usize,
+    stage: Stage,
     errors: usize,
     too_long: bool,
     failed: bool,
     proto: bool,
     types: Vec<String>,
+    #[serde(default)]
+    helpers: Vec<String>,
 }
 
 impl TranslationResult {
@@ -144,6 +167,32 @@ impl TranslationResult {
     }
 }
 
+#[derive(Default, Serialize, Deserialize)]
+struct Checkpoint {
+    new_type_names: Vec<(TypeSort, String, String)>,
+    new_term_names: BTreeMap<String, String>,
+    translated_types: Vec<(TypeSort, String, TranslationResult)>,
+    translated_variables: BTreeMap<String, TranslationResult>,
+    translated_functions: BTreeMap<String, TranslationResult>,
+    #[serde(default)]
+    new_macro_names: BTreeMap<String, String>,
+    #[serde(default)]
+    translated_macros: BTreeMap<String, TranslationResult>,
+    #[serde(default)]
+    unsafe_counts: BTreeMap<String, UnsafeReduction>,
+    #[serde(default)]
+    missing_functions: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    renames: Vec<Rename>,
+}
+
+/// The unsafe code of a function before and after the de-unsafing pass.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct UnsafeReduction {
+    before: UnsafeCount,
+    after: UnsafeCount,
+}
+
 #[derive(Debug)]
 struct DependencyPrefixes {
     translation_prefix: Vec<String>,
@@ -248,7 +297,7 @@ struct SigDiff {
     rust_signature_ty: FunTySig,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
 pub enum SigDiffReason {
     Option,
     String,
@@ -262,6 +311,90 @@ pub enum SigDiffReason {
     Etc,
 }
 
+#[derive(Debug, Serialize)]
+struct ProgramSize {
+    lines: usize,
+    types: usize,
+    variables: usize,
+    protos: usize,
+    functions: usize,
+    calls: usize,
+    macros: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ErrorStat {
+    errors: usize,
+    protos: usize,
+    too_long: usize,
+    failed: usize,
+    with_errors: usize,
+    without_errors: usize,
+    without_transitive_errors: usize,
+    without_error_names: Vec<String>,
+    without_transitive_error_names: Vec<String>,
+    mismatched: usize,
+    /// Functions among `with_errors` that call undefined functions.
+    missing_functions: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct TypeStat {
+    rust_only: usize,
+    mixed: usize,
+    c_only: usize,
+    rust_types: BTreeMap<String, usize>,
+    c_types: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct LlmStat {
+    request_tokens: usize,
+    response_tokens: usize,
+    response_time: f32,
+    cache_hits: usize,
+    cache_misses: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ItemStatus {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort: Option<TypeSort>,
+    new_name: Option<String>,
+    proto: bool,
+    too_long: bool,
+    failed: bool,
+    errors: usize,
+    stage: Stage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mismatch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sig_diff: Option<SigDiffReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unsafe_code: Option<UnsafeReduction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing_functions: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    helper_functions: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    program_size: ProgramSize,
+    variable_errors: ErrorStat,
+    function_errors: ErrorStat,
+    types: Vec<ItemStatus>,
+    macros: Vec<ItemStatus>,
+    variables: Vec<ItemStatus>,
+    functions: Vec<ItemStatus>,
+    helper_functions: usize,
+    sig_diffs: BTreeMap<SigDiffReason, usize>,
+    type_usage: TypeStat,
+    llm: LlmStat,
+    renames: Vec<Rename>,
+}
+
 static PREAMBLE: &str = "extern crate once_cell;extern crate libc;";
 
 impl<'ast> Translator<'ast> {
@@ -275,6 +408,16 @@ impl<'ast> Translator<'ast> {
         let enums = program.enums();
         let (variables, protos) = program.variables();
         let functions = program.functions();
+        let macros = program
+            .macros
+            .iter()
+            .map(|(name, m)| (name.as_str(), m))
+            .collect();
+        let term_macros = program
+            .macro_uses
+            .iter()
+            .map(|(name, macros)| (name.as_str(), macros.iter().map(|m| m.as_str()).collect()))
+            .collect();
 
         let mut cg: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
         for (name, t) in &typedefs {
@@ -317,11 +460,7 @@ impl<'ast> Translator<'ast> {
             .map(|(name, function)| {
                 (
                     *name,
-                    function
-                        .dependencies
-                        .iter()
-                        .map(|v| v.node.name.as_str())
-                        .collect(),
+                    function.dependencies.iter().map(|v| v.symbol).collect(),
                 )
             })
             .collect();
@@ -334,7 +473,7 @@ impl<'ast> Translator<'ast> {
                     variable
                         .dependencies
                         .iter()
-                        .map(|callee| callee.node.name.as_str())
+                        .map(|callee| callee.symbol)
                         .collect(),
                 )
             })
@@ -350,7 +489,7 @@ impl<'ast> Translator<'ast> {
                     function
                         .callees
                         .iter()
-                        .map(|callee| callee.node.name.as_str())
+                        .map(|callee| callee.symbol)
                         .collect(),
                 )
             })
@@ -370,12 +509,14 @@ impl<'ast> Translator<'ast> {
             variables,
             protos,
             functions,
+            macros,
             custom_types,
             transitive_types,
             transitive_variables,
             transitive_functions,
             term_types,
             function_variables,
+            term_macros,
             type_graph,
             type_elem_map,
             variable_graph,
@@ -386,33 +527,412 @@ impl<'ast> Translator<'ast> {
             client,
             new_type_names: BTreeMap::new(),
             new_term_names: BTreeMap::new(),
+            new_macro_names: BTreeMap::new(),
             inner: RwLock::new(inner),
             config,
+            checkpoint: None,
+            resumed: false,
+            provenance: None,
+            overrides: Overrides::default(),
+            renames: vec![],
+        }
+    }
+
+    pub fn set_checkpoint(&mut self, path: String) {
+        self.checkpoint = Some(path);
+    }
+
+    pub fn record_provenance(&mut self) {
+        self.provenance = Some(Provenance::default());
+    }
+
+    pub fn write_provenance(&self, path: &str) {
+        self.provenance
+            .as_ref()
+            .expect("provenance is not recorded")
+            .write(path)
+            .unwrap();
+    }
+
+    fn trail<F: FnOnce(&mut Trail)>(&self, key: &str, f: F) {
+        if let Some(provenance) = &self.provenance {
+            provenance.record(key, f);
+        }
+    }
+
+    /// Fails on a key that is the C name of statics in several files.
+    pub fn set_overrides(&mut self, overrides: Overrides) -> Result<(), String> {
+        let type_keys = overrides.type_names.keys().chain(overrides.types.keys());
+        for key in type_keys {
+            if !self.config.quiet && !self.custom_types.iter().any(|ty| ty.name == key) {
+                println!("override matches no type: {}", key);
+            }
+        }
+        let term_keys = overrides
+            .names
+            .keys()
+            .chain(overrides.signatures.keys())
+            .chain(overrides.variables.keys())
+            .chain(overrides.functions.keys());
+        let symbols = || {
+            self.variables
+                .keys()
+                .chain(self.protos.keys())
+                .chain(self.functions.keys())
+        };
+        for key in term_keys {
+            if symbols().any(|symbol| symbol == key) {
+                continue;
+            }
+            let statics: Vec<_> = symbols()
+                .filter(|symbol| c_parser::c_name(symbol) == key)
+                .copied()
+                .collect();
+            if !statics.is_empty() {
+                let statics = statics.join(" or ");
+                return Err(format!(
+                    "`{}` is static in several files; use {}",
+                    key, statics
+                ));
+            }
+            if !self.config.quiet {
+                println!("override matches no variable or function: {}", key);
+            }
+        }
+        self.overrides = overrides;
+        Ok(())
+    }
+
+    // a struct and the typedefs aliasing it share their overrides
+    fn c_type_names(&self, ty: CustomType<'ast>) -> Vec<&'ast str> {
+        let target = match self.typedefs.get(ty.name) {
+            Some(typedef) if ty.sort == TypeSort::Typedef && typedef.is_struct_alias => {
+                typedef.dependencies[0].typ
+            }
+            _ => ty,
+        };
+        let mut names = vec![target.name];
+        names.extend(
+            self.typedefs
+                .iter()
+                .filter(|(_, typedef)| {
+                    typedef.is_struct_alias && typedef.dependencies[0].typ == target
+                })
+                .map(|(name, _)| *name),
+        );
+        names
+    }
+
+    fn type_name_override(&self, ty: CustomType<'ast>) -> Option<String> {
+        self.c_type_names(ty)
+            .into_iter()
+            .find_map(|name| self.overrides.type_name(name))
+    }
+
+    fn hand_written_type(&self, ty: CustomType<'ast>) -> Option<&str> {
+        self.c_type_names(ty)
+            .into_iter()
+            .find_map(|name| self.overrides.types.get(name))
+            .map(|code| code.as_str())
+    }
+
+    /// Type checks a hand-written item without asking the language model to fix it.
+    fn hand_written(&self, code: &str, checking_prefix: &str) -> Option<TranslationResult> {
+        let names = BTreeSet::new();
+        let mut items = compiler::parse(code)?;
+        let uses = Self::take_uses(&mut items);
+        let code: String = items.iter().map(|i| i.get_code() + "\n").collect();
+        let code = compiler::resolve_imports(&code, &uses.join(""))?;
+        let items = compiler::parse(&code)?;
+        let ctxt = FixContext::new(checking_prefix, code, &names);
+        let (stage, errors) = ctxt
+            .result
+            .as_ref()
+            .map(|res| (res.stage, res.errors.len()))
+            .unwrap_or_default();
+        Some(TranslationResult {
+            items,
+            stage,
+            errors,
+            too_long: false,
+            failed: false,
+            proto: false,
+            types: vec![],
+            helpers: vec![],
+        })
+    }
+
+    /// Like `hand_written`, but the items after the function are nested in it as helpers.
+    fn hand_written_function(
+        &self,
+        code: &str,
+        checking_prefix: &str,
+    ) -> Option<TranslationResult> {
+        let mut items = compiler::parse(code)?;
+        let uses = Self::take_uses(&mut items);
+        let (function, helpers) = items.split_first()?;
+        function.as_function()?;
+        let code: Vec<_> = helpers.iter().map(|i| i.get_code()).collect();
+        let function = compiler::nest_functions(&function.get_code(), &code)?;
+        let mut translated = self.hand_written(&(uses.concat() + &function), checking_prefix)?;
+        translated.types = compiler::get_types(&translated.items[0].code).unwrap_or_default();
+        translated.helpers = helpers.iter().map(|i| i.name.clone()).collect();
+        Some(translated)
+    }
+
+    /// Restores the names and the finished translations from the checkpoint file, if it exists.
+    pub fn resume(&mut self) -> bool {
+        let path = self.checkpoint.as_ref().expect("no checkpoint file");
+        let content = some_or!(fs::read_to_string(path).ok(), return false);
+        let checkpoint: Checkpoint = serde_json::from_str(&content).unwrap();
+
+        for (sort, name, new_name) in checkpoint.new_type_names {
+            let ty = self.custom_type(sort, &name);
+            self.new_type_names.insert(ty, new_name);
+        }
+        for (name, new_name) in checkpoint.new_term_names {
+            let name = self.term_name(&name);
+            self.new_term_names.insert(name, new_name);
+        }
+        self.renames = checkpoint.renames;
+        for (name, new_name) in checkpoint.new_macro_names {
+            let name = self.macro_name(&name);
+            self.new_macro_names.insert(name, new_name);
+        }
+        // the overrides file may have been edited since the checkpoint was saved
+        let pinned: Vec<_> = self
+            .new_type_names
+            .keys()
+            .filter_map(|ty| Some((*ty, self.type_name_override(*ty)?)))
+            .collect();
+        self.new_type_names.extend(pinned);
+        for (name, new_name) in &mut self.new_term_names {
+            if let Some(pinned) = self.overrides.term_name(name) {
+                *new_name = pinned;
+            }
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        for (sort, name, translated) in checkpoint.translated_types {
+            let ty = self.custom_type(sort, &name);
+            inner.add_names(&translated);
+            inner.translated_types.insert(ty, translated);
+        }
+        for (name, translated) in checkpoint.translated_macros {
+            let name = self.macro_name(&name);
+            inner.add_names(&translated);
+            inner.translated_macros.insert(name, translated);
+        }
+        for (name, translated) in checkpoint.translated_variables {
+            let name = self.term_name(&name);
+            inner.add_names(&translated);
+            inner.translated_variables.insert(name, translated);
+        }
+        for (name, translated) in checkpoint.translated_functions {
+            let name = self.term_name(&name);
+            inner.add_names(&translated);
+            inner.translated_functions.insert(name, translated);
+            if self.functions.contains_key(name) {
+                inner.finished_functions.insert(name);
+            }
+        }
+        for (name, reduction) in checkpoint.unsafe_counts {
+            let name = self.term_name(&name);
+            inner.unsafe_counts.insert(name, reduction);
+        }
+        for (name, missing) in checkpoint.missing_functions {
+            let name = self.term_name(&name);
+            inner.missing_functions.insert(name, missing);
+        }
+        drop(inner);
+
+        self.resumed = true;
+        true
+    }
+
+    fn custom_type(&self, sort: TypeSort, name: &str) -> CustomType<'ast> {
+        *self
+            .custom_types
+            .iter()
+            .find(|ty| ty.sort == sort && ty.name == name)
+            .unwrap_or_else(|| panic!("unknown type in checkpoint: {}", name))
+    }
+
+    fn term_name(&self, name: &str) -> &'ast str {
+        self.variables
+            .get_key_value(name)
+            .or_else(|| self.protos.get_key_value(name))
+            .map(|(name, _)| *name)
+            .or_else(|| self.functions.get_key_value(name).map(|(name, _)| *name))
+            .unwrap_or_else(|| panic!("unknown term in checkpoint: {}", name))
+    }
+
+    fn macro_name(&self, name: &str) -> &'ast str {
+        self.macros
+            .get_key_value(name)
+            .map(|(name, _)| *name)
+            .unwrap_or_else(|| panic!("unknown macro in checkpoint: {}", name))
+    }
+
+    fn used_macros(&self, name: &str) -> Vec<&'ast str> {
+        self.term_macros
+            .get(name)
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    fn macro_dependencies(&self, name: &'ast str) -> BTreeSet<&'ast str> {
+        let mut deps = BTreeSet::new();
+        let mut stack = vec![name];
+        while let Some(name) = stack.pop() {
+            for id in self.macros[name].identifiers() {
+                if let Some((id, _)) = self.macros.get_key_value(id) {
+                    if deps.insert(*id) {
+                        stack.push(id);
+                    }
+                }
+            }
+        }
+        deps.remove(name);
+        deps
+    }
+
+    fn push_constants<'a>(&'a self, name: &str, vec: &mut Vec<(Span, &'a str)>) {
+        let inner = self.inner.read().unwrap();
+        let constants = self.program.constant_uses.get(name).into_iter().flatten();
+        for (span, m) in constants {
+            let translated = inner.translated_macros.get(m.as_str());
+            if !translated.map_or(false, |translated| !translated.failed)
+                || vec.iter().any(|(s, _)| c_parser::overlap(*s, *span))
+            {
+                continue;
+            }
+            vec.push((*span, self.new_macro_names[m.as_str()].as_str()));
+        }
+    }
+
+    fn macro_definitions(&self, macros: &[&'ast str]) -> String {
+        let names = self
+            .new_macro_names
+            .iter()
+            .map(|(name, new_name)| (*name, new_name.as_str()))
+            .collect();
+        let inner = self.inner.read().unwrap();
+        macros
+            .iter()
+            .filter(|name| {
+                let translated = inner.translated_macros.get(*name);
+                translated.map_or(false, |translated| !translated.failed)
+            })
+            .map(|name| format!("{}\n", self.macros[name].to_string(&names)))
+            .collect()
+    }
+
+    fn save_checkpoint(&self) {
+        let path = some_or!(self.checkpoint.as_ref(), return);
+        let inner = self.inner.read().unwrap();
+        let checkpoint = Checkpoint {
+            new_type_names: self
+                .new_type_names
+                .iter()
+                .map(|(ty, new_name)| (ty.sort, ty.name.to_string(), new_name.clone()))
+                .collect(),
+            new_term_names: self
+                .new_term_names
+                .iter()
+                .map(|(name, new_name)| (name.to_string(), new_name.clone()))
+                .collect(),
+            translated_types: inner
+                .translated_types
+                .iter()
+                .map(|(ty, translated)| (ty.sort, ty.name.to_string(), translated.clone()))
+                .collect(),
+            translated_variables: inner
+                .translated_variables
+                .iter()
+                .map(|(name, translated)| (name.to_string(), translated.clone()))
+                .collect(),
+            translated_functions: inner
+                .translated_functions
+                .iter()
+                .filter(|(name, _)| {
+                    inner.finished_functions.contains(*name) || self.protos.contains_key(*name)
+                })
+                .map(|(name, translated)| (name.to_string(), translated.clone()))
+                .collect(),
+            new_macro_names: self
+                .new_macro_names
+                .iter()
+                .map(|(name, new_name)| (name.to_string(), new_name.clone()))
+                .collect(),
+            translated_macros: inner
+                .translated_macros
+                .iter()
+                .map(|(name, translated)| (name.to_string(), translated.clone()))
+                .collect(),
+            unsafe_counts: inner
+                .unsafe_counts
+                .iter()
+                .map(|(name, reduction)| (name.to_string(), *reduction))
+                .collect(),
+            missing_functions: inner
+                .missing_functions
+                .iter()
+                .map(|(name, missing)| (name.to_string(), missing.clone()))
+                .collect(),
+            renames: self.renames.clone(),
+        };
+        drop(inner);
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, serde_json::to_string(&checkpoint).unwrap()).unwrap();
+        fs::rename(tmp, path).unwrap();
+    }
+
+    fn program_size(&self) -> ProgramSize {
+        ProgramSize {
+            lines: self.lines_of_code(),
+            types: self.typedefs.len() + self.structs.len() + self.enums.len(),
+            variables: self.variables.len(),
+            protos: self.protos.len(),
+            functions: self.functions.len(),
+            calls: self.functions.values().map(|f| f.calls).sum(),
+            macros: self.macros.len(),
         }
     }
 
     pub fn show_information(&self) {
-        let lines = self.lines_of_code();
-        let types = self.typedefs.len() + self.structs.len() + self.enums.len();
-        let variables = self.variables.len();
-        let protos = self.protos.len();
-        let functions = self.functions.len();
-        let calls: usize = self.functions.values().map(|f| f.calls).sum();
+        let s = self.program_size();
         println!(
             "{}\n{}\n{}\n{}\n{}\n{}",
-            lines, types, variables, protos, functions, calls
+            s.lines, s.types, s.variables, s.protos, s.functions, s.calls
         );
     }
 
+    fn llm_stat(&self) -> LlmStat {
+        LlmStat {
+            request_tokens: self.client.request_tokens(),
+            response_tokens: self.client.response_tokens(),
+            response_time: self.client.response_time(),
+            cache_hits: self.client.cache_hits(),
+            cache_misses: self.client.cache_misses(),
+        }
+    }
+
     pub fn show_openai_stat(&self) {
+        let s = self.llm_stat();
         println!(
             "{}\n{}\n{}",
-            self.client.request_tokens(),
-            self.client.response_tokens(),
-            self.client.response_time(),
+            s.request_tokens, s.response_tokens, s.response_time
         );
     }
 
+    pub fn show_cache_stat(&self) {
+        let s = self.llm_stat();
+        println!("{}\n{}", s.cache_hits, s.cache_misses);
+    }
+
     fn lines_of_code(&self) -> usize {
         let spans: Vec<_> = self
             .typedefs
@@ -453,65 +973,76 @@ impl<'ast> Translator<'ast> {
         lines
     }
 
-    pub fn show_error_num(&self) {
-        let inner = self.inner.read().unwrap();
-        fn aux(
-            translated: &BTreeMap<&str, TranslationResult>,
-            transitive: &BTreeMap<&str, BTreeSet<&str>>,
-        ) {
-            let mut errors = 0;
-            let mut protos = 0;
-            let mut longs = 0;
-            let mut fails = 0;
-            let mut w_errors = 0;
-            let mut wo_errors = 0;
-            let mut wo_error_names = vec![];
-            let mut no_trans_errors = 0;
-            let mut no_trans_error_names = vec![];
-            for (name, t) in translated {
-                if t.proto {
-                    protos += 1;
-                } else if t.too_long {
-                    longs += 1;
-                } else if t.failed {
-                    fails += 1;
-                } else if t.errors > 0 {
-                    w_errors += 1;
-                    errors += t.errors;
-                } else {
-                    wo_errors += 1;
-                    wo_error_names.push(*name);
-                    let callees = &transitive[name];
-                    if callees.iter().all(|c| {
-                        let t = some_or!(translated.get(c), return true);
-                        t.no_error()
-                    }) {
-                        no_trans_errors += 1;
-                        no_trans_error_names.push(*name);
-                    }
+    fn error_stat(
+        translated: &BTreeMap<&str, TranslationResult>,
+        transitive: &BTreeMap<&str, BTreeSet<&str>>,
+        mismatches: &BTreeMap<&str, String>,
+        missing_functions: &BTreeMap<&str, Vec<String>>,
+    ) -> ErrorStat {
+        let mut stat = ErrorStat::default();
+        for (name, t) in translated {
+            if t.proto {
+                stat.protos += 1;
+            } else if t.too_long {
+                stat.too_long += 1;
+            } else if t.failed {
+                stat.failed += 1;
+            } else if t.errors > 0 {
+                stat.with_errors += 1;
+                if missing_functions.contains_key(name) {
+                    stat.missing_functions += 1;
+                }
+                stat.errors += t.errors;
+            } else if mismatches.contains_key(name) {
+                stat.mismatched += 1;
+            } else {
+                stat.without_errors += 1;
+                stat.without_error_names.push(name.to_string());
+                let callees = &transitive[name];
+                if callees.iter().all(|c| {
+                    let t = some_or!(translated.get(c), return true);
+                    t.no_error() && !mismatches.contains_key(c)
+                }) {
+                    stat.without_transitive_errors += 1;
+                    stat.without_transitive_error_names.push(name.to_string());
                 }
             }
-            let wo_error_names: String = wo_error_names.iter().copied().intersperse(" ").collect();
-            let no_trans_error_names: String = no_trans_error_names
-                .iter()
-                .copied()
-                .intersperse(" ")
-                .collect();
+        }
+        stat
+    }
+
+    pub fn show_error_num(&self) {
+        let inner = self.inner.read().unwrap();
+        let stats = [
+            Self::error_stat(
+                &inner.translated_variables,
+                &self.transitive_variables,
+                &BTreeMap::new(),
+                &BTreeMap::new(),
+            ),
+            Self::error_stat(
+                &inner.translated_functions,
+                &self.transitive_functions,
+                &inner.mismatches,
+                &inner.missing_functions,
+            ),
+        ];
+        for s in stats {
             println!(
-                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
-                errors,
-                protos,
-                longs,
-                fails,
-                w_errors,
-                wo_errors,
-                no_trans_errors,
-                wo_error_names,
-                no_trans_error_names
+                "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+                s.errors,
+                s.protos,
+                s.too_long,
+                s.failed,
+                s.with_errors,
+                s.without_errors,
+                s.without_transitive_errors,
+                s.without_error_names.join(" "),
+                s.without_transitive_error_names.join(" "),
+                s.mismatched,
+                s.missing_functions
             );
         }
-        aux(&inner.translated_variables, &self.transitive_variables);
-        aux(&inner.translated_functions, &self.transitive_functions);
     }
 
     pub fn per_stage(&self) -> String {
@@ -520,6 +1051,7 @@ impl<'ast> Translator<'ast> {
         for res in inner
             .translated_types
             .values()
+            .chain(inner.translated_macros.values())
             .chain(inner.translated_variables.values())
             .chain(inner.translated_functions.values())
         {
@@ -539,54 +1071,221 @@ impl<'ast> Translator<'ast> {
             .join("\n")
     }
 
-    pub fn show_type(&self) {
+    fn type_stat(&self) -> TypeStat {
         let inner = self.inner.read().unwrap();
-        let mut fulls = 0;
-        let mut partials = 0;
-        let mut uns = 0;
-        let mut rtys: BTreeMap<_, usize> = BTreeMap::new();
-        let mut ctys: BTreeMap<_, usize> = BTreeMap::new();
+        let mut stat = TypeStat::default();
         for f in inner.translated_functions.values() {
             let tys = &f.types;
             if f.proto || f.too_long || f.failed {
                 continue;
             }
             if tys.iter().all(|ty| !compiler::is_c_type(ty)) {
-                fulls += 1;
+                stat.rust_only += 1;
             } else if tys.iter().all(|ty| compiler::is_c_type(ty)) {
-                uns += 1;
+                stat.c_only += 1;
             } else {
-                partials += 1;
+                stat.mixed += 1;
             }
             for ty in tys {
-                if compiler::is_c_type(ty) {
-                    *ctys.entry(ty).or_default() += 1;
+                let map = if compiler::is_c_type(ty) {
+                    &mut stat.c_types
                 } else {
-                    *rtys.entry(ty).or_default() += 1;
-                }
+                    &mut stat.rust_types
+                };
+                *map.entry(ty.clone()).or_default() += 1;
             }
         }
-        let rty_n = rtys.values().sum::<usize>();
-        let rty_kind_n = rtys.len();
-        let rtys_str = rtys
-            .into_iter()
-            .map(|(ty, n)| format!("{} {}", ty, n))
-            .collect::<Vec<_>>()
-            .join(", ");
-        let cty_n = ctys.values().sum::<usize>();
-        let cty_kind_n = ctys.len();
-        let ctys_str = ctys
-            .into_iter()
-            .map(|(ty, n)| format!("{} {}", ty, n))
-            .collect::<Vec<_>>()
-            .join(", ");
+        stat
+    }
 
+    pub fn show_type(&self) {
+        let stat = self.type_stat();
+        let aux = |tys: &BTreeMap<String, usize>| {
+            let n = tys.values().sum::<usize>();
+            let s = tys
+                .iter()
+                .map(|(ty, n)| format!("{} {}", ty, n))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{}\n{}\n{}", n, tys.len(), s)
+        };
         println!(
-            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
-            fulls, partials, uns, rty_n, rty_kind_n, rtys_str, cty_n, cty_kind_n, ctys_str
+            "{}\n{}\n{}\n{}\n{}",
+            stat.rust_only,
+            stat.mixed,
+            stat.c_only,
+            aux(&stat.rust_types),
+            aux(&stat.c_types)
         );
     }
 
+    pub fn show_unsafe(&self) {
+        let inner = self.inner.read().unwrap();
+        let (mut before, mut after) = (0, 0);
+        for (name, reduction) in &inner.unsafe_counts {
+            let new_name = self.new_term_names.get(name).unwrap();
+            let count = |c: &UnsafeCount| {
+                format!(
+                    "{} ({}, {}, {})",
+                    c.total(),
+                    c.blocks,
+                    c.raw_derefs,
+                    c.static_muts
+                )
+            };
+            println!(
+                "{} {} {}",
+                new_name,
+                count(&reduction.before),
+                count(&reduction.after)
+            );
+            before += reduction.before.total();
+            after += reduction.after.total();
+        }
+        println!("{}\n{}", before, after);
+    }
+
+    /// Why the translated signature of `name` differs from its C signature, if it does.
+    fn sig_diff_reason(&self, name: &str, translated: &TranslationResult) -> Option<SigDiffReason> {
+        let func = self.functions.get(name)?;
+        let new_name = self.new_term_names.get(name)?;
+        let info = translated.items.iter().find_map(|item| match &item.sort {
+            ItemSort::Function(info) if &item.name == new_name => Some(info),
+            _ => None,
+        })?;
+        let map: BTreeMap<_, _> = self
+            .new_type_names
+            .iter()
+            .map(|(ty, new_name)| (new_name.as_str(), ty.name))
+            .collect();
+        let rust = info.signature_ty.clone().into_c(&map);
+        let c = &func.type_signature;
+        if &rust == c {
+            return None;
+        }
+        let rust = &info.signature_ty;
+        let is_tuple =
+            |ty: &compiler::Type| matches!(ty, compiler::Type::Tup(ts) if !ts.is_empty());
+        let void_ptr = compiler::Type::Ptr(Box::new(compiler::UNIT), true);
+        let reason = if rust.generic {
+            SigDiffReason::Generic
+        } else if rust.contains("Option") {
+            SigDiffReason::Option
+        } else if rust.contains("String") || rust.contains("str") {
+            SigDiffReason::String
+        } else if rust.contains("Vec") {
+            SigDiffReason::Vec
+        } else if is_tuple(&rust.ret) || rust.params.iter().any(is_tuple) {
+            SigDiffReason::Tuple
+        } else if rust.contains("File") {
+            SigDiffReason::File
+        } else if rust.ret == compiler::Type::Never {
+            SigDiffReason::Never
+        } else if c.ret == void_ptr || c.params.contains(&void_ptr) {
+            SigDiffReason::VoidPtr
+        } else if matches!(c.ret, compiler::Type::Ptr(_, _))
+            || c.params
+                .iter()
+                .any(|ty| matches!(ty, compiler::Type::Ptr(_, _)))
+        {
+            SigDiffReason::Ptr
+        } else {
+            SigDiffReason::Etc
+        };
+        Some(reason)
+    }
+
+    /// Writes the statistics of the `show_*` methods and the status of every item as JSON.
+    pub fn write_report(&self, path: &str) {
+        let item_status =
+            |name: String, new_name: Option<&String>, t: &TranslationResult| ItemStatus {
+                name,
+                sort: None,
+                new_name: new_name.cloned(),
+                proto: t.proto,
+                too_long: t.too_long,
+                failed: t.failed,
+                errors: t.errors,
+                stage: t.stage,
+                mismatch: None,
+                sig_diff: None,
+                unsafe_code: None,
+                missing_functions: vec![],
+                helper_functions: vec![],
+            };
+        let program_size = self.program_size();
+        let type_usage = self.type_stat();
+        let inner = self.inner.read().unwrap();
+        let types = inner
+            .translated_types
+            .iter()
+            .map(|(ty, t)| ItemStatus {
+                sort: Some(ty.sort),
+                ..item_status(ty.name.to_string(), self.new_type_names.get(ty), t)
+            })
+            .collect();
+        let macros = inner
+            .translated_macros
+            .iter()
+            .map(|(name, t)| item_status(name.to_string(), self.new_macro_names.get(name), t))
+            .collect();
+        let variables = inner
+            .translated_variables
+            .iter()
+            .map(|(name, t)| item_status(name.to_string(), self.new_term_names.get(name), t))
+            .collect();
+        let mut sig_diffs: BTreeMap<_, usize> = BTreeMap::new();
+        let functions: Vec<_> = inner
+            .translated_functions
+            .iter()
+            .map(|(name, t)| {
+                let mut status = item_status(name.to_string(), self.new_term_names.get(name), t);
+                status.mismatch = inner.mismatches.get(name).cloned();
+                status.unsafe_code = inner.unsafe_counts.get(name).copied();
+                status.missing_functions = inner
+                    .missing_functions
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default();
+                status.helper_functions = t.helpers.clone();
+                if !t.proto {
+                    status.sig_diff = self.sig_diff_reason(name, t);
+                }
+                if let Some(reason) = status.sig_diff {
+                    *sig_diffs.entry(reason).or_default() += 1;
+                }
+                status
+            })
+            .collect();
+        let helper_functions = functions.iter().map(|f| f.helper_functions.len()).sum();
+        let report = Report {
+            program_size,
+            variable_errors: Self::error_stat(
+                &inner.translated_variables,
+                &self.transitive_variables,
+                &BTreeMap::new(),
+                &BTreeMap::new(),
+            ),
+            function_errors: Self::error_stat(
+                &inner.translated_functions,
+                &self.transitive_functions,
+                &inner.mismatches,
+                &inner.missing_functions,
+            ),
+            types,
+            macros,
+            variables,
+            functions,
+            helper_functions,
+            sig_diffs,
+            type_usage,
+            llm: self.llm_stat(),
+            renames: self.renames.clone(),
+        };
+        let file = fs::File::create(path).unwrap();
+        serde_json::to_writer_pretty(file, &report).unwrap();
+    }
+
     fn existing_names(&self) -> BTreeSet<String> {
         let inner = self.inner.read().unwrap();
         inner
@@ -602,10 +1301,12 @@ impl<'ast> Translator<'ast> {
         let mut items: BTreeMap<(u8, &str), &ParsedItem> = BTreeMap::new();
 
         for item in item_vec {
+            // `macro_rules!` macros can only be used after their definitions
             let n = match &item.sort {
-                ItemSort::Type(_) => 0,
-                ItemSort::Variable(_) => 1,
-                ItemSort::Function(_) => 2,
+                ItemSort::Macro => 0,
+                ItemSort::Type(_) => 1,
+                ItemSort::Variable(_) => 2,
+                ItemSort::Function(_) => 3,
                 _ => panic!(),
             };
             items.insert((n, &item.name), item);
@@ -615,11 +1316,13 @@ impl<'ast> Translator<'ast> {
     }
 
     pub fn code(&self) -> String {
+        let main = self.main_function("");
         let inner = self.inner.read().unwrap();
         let items = Self::dedup_items(
             inner
                 .translated_types
                 .values()
+                .chain(inner.translated_macros.values())
                 .chain(inner.translated_variables.values())
                 .chain(inner.translated_functions.values())
                 .flat_map(|t| &t.items)
@@ -627,16 +1330,347 @@ impl<'ast> Translator<'ast> {
         );
         std::iter::once(PREAMBLE.to_string())
             .chain(items.into_iter().map(|i| i.get_code()))
-            .chain(std::iter::once("fn main() {}".to_string()))
+            .chain(std::iter::once(main))
             .intersperse("\n".to_string())
             .collect()
     }
 
+    /// Runs the C and Rust versions of the functions with scalar signatures on the same inputs.
+    pub fn test_behavior(&self, inputs: Option<&str>) {
+        let inputs: BTreeMap<String, Vec<serde_json::Value>> = inputs
+            .map(|path| serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap())
+            .unwrap_or_default();
+
+        let mut inner = self.inner.write().unwrap();
+        let mut targets = vec![];
+        for (name, func) in &self.functions {
+            if *name == "main" {
+                continue;
+            }
+            let translated = some_or!(inner.translated_functions.get(name), continue);
+            let new_name = &self.new_term_names[name];
+            let info = translated.items.iter().find_map(|item| match &item.sort {
+                ItemSort::Function(info) if &item.name == new_name => Some(info),
+                _ => None,
+            });
+            let info = some_or!(info, continue);
+            let mut target = some_or!(
+                differential::Target::new(
+                    name,
+                    func.path,
+                    new_name,
+                    &func.type_signature,
+                    &info.signature_ty,
+                    &info.signature,
+                ),
+                continue
+            );
+            if let Some(cases) = inputs.get(*name) {
+                target.set_inputs(cases);
+            }
+            targets.push(target);
+        }
+
+        // functions with errors are replaced with stubs so that the crate compiles
+        let items = Self::dedup_items(
+            inner
+                .translated_types
+                .values()
+                .chain(inner.translated_variables.values())
+                .chain(inner.translated_functions.values())
+                .flat_map(|t| &t.items)
+                .collect(),
+        );
+        let with_errors: BTreeSet<_> = inner
+            .translated_functions
+            .values()
+            .filter(|t| !t.no_error())
+            .flat_map(|t| &t.items)
+            .map(|i| &i.name)
+            .collect();
+        let rust_code: String = std::iter::once(PREAMBLE.to_string())
+            .chain(items.into_iter().map(|i| {
+                if matches!(i.sort, ItemSort::Function(_)) && with_errors.contains(&i.name) {
+                    i.get_checking_code()
+                } else {
+                    i.get_code()
+                }
+            }))
+            .intersperse("\n".to_string())
+            .collect();
+        let c_codes: BTreeMap<_, _> = self
+            .program
+            .parses
+            .iter()
+            .map(|(path, parse)| {
+                let source = c_parser::compilable_source(&parse.source);
+                (path.clone(), source.to_string())
+            })
+            .collect();
+
+        let outcomes = match differential::run(&c_codes, &rust_code, &targets) {
+            Ok(outcomes) => outcomes,
+            Err(e) => {
+                if !self.config.quiet {
+                    println!("Differential testing failed:\n{}", e);
+                }
+                return;
+            }
+        };
+        for (name, outcome) in outcomes {
+            let name = some_or!(self.functions.get_key_value(name.as_str()), continue).0;
+            if let differential::Outcome::Differs { args, c, rust } = outcome {
+                let msg = format!("{}({}): C {}, Rust {}", name, args, c, rust);
+                if !self.config.quiet {
+                    println!("Behavior differs: {}", msg);
+                }
+                inner.mismatches.insert(name, msg);
+            } else {
+                inner.mismatches.remove(name);
+            }
+        }
+    }
+
+    /// The files of `src`, with one module per C source file and a `common` one for types.
+    pub fn module_files(&self) -> Vec<(String, String)> {
+        let mut paths: BTreeSet<&str> = self.functions.values().map(|f| f.path).collect();
+        paths.extend(self.variables.values().map(|v| v.path));
+        let mut module_names: BTreeMap<&str, String> = BTreeMap::new();
+        let mut used: BTreeSet<String> = ["common", "main"].iter().map(|s| s.to_string()).collect();
+        for path in paths {
+            let stem = Path::new(path)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("");
+            let mut name = module_name(stem);
+            if names::is_keyword(&name) || used.contains(&name) {
+                name.push_str("_c");
+            }
+            let base = name.clone();
+            let mut i = 1;
+            while used.contains(&name) {
+                name = format!("{}_{}", base, i);
+                i += 1;
+            }
+            used.insert(name.clone());
+            module_names.insert(path, name);
+        }
+
+        let term_module = |name: &str| {
+            let path = self
+                .functions
+                .get(name)
+                .map(|f| f.path)
+                .or_else(|| self.variables.get(name).map(|v| v.path))?;
+            Some(module_names[path].as_str())
+        };
+        let main_module = self
+            .functions
+            .get("main")
+            .map(|f| format!("{}::", module_names[f.path]))
+            .unwrap_or_default();
+        let entry = self.main_function(&main_module);
+
+        let inner = self.inner.read().unwrap();
+        let mut modules: BTreeMap<&str, Vec<&ParsedItem>> = BTreeMap::new();
+        modules.insert("common", vec![]);
+        let mut imports: BTreeMap<&str, BTreeMap<&str, BTreeSet<&str>>> = BTreeMap::new();
+        let mut seen: BTreeSet<(u8, &str)> = BTreeSet::new();
+        let owned: Vec<(Option<&str>, &TranslationResult)> = inner
+            .translated_types
+            .values()
+            .chain(inner.translated_macros.values())
+            .map(|t| (None, t))
+            .chain(
+                inner
+                    .translated_variables
+                    .iter()
+                    .map(|(name, t)| (Some(*name), t)),
+            )
+            .chain(
+                inner
+                    .translated_functions
+                    .iter()
+                    .map(|(name, t)| (if t.proto { None } else { Some(*name) }, t)),
+            )
+            .collect();
+        for (owner, translated) in owned {
+            let module = owner.and_then(term_module).unwrap_or("common");
+            for item in &translated.items {
+                let (n, module) = match &item.sort {
+                    ItemSort::Type(_) => (0, "common"),
+                    ItemSort::Variable(_) => (1, module),
+                    ItemSort::Function(_) => (2, module),
+                    ItemSort::Macro => (3, "common"),
+                    _ => panic!(),
+                };
+                if seen.insert((n, &item.name)) {
+                    modules.entry(module).or_default().push(item);
+                }
+            }
+            let owner = some_or!(owner, continue);
+            let deps = if let Some(func) = self.functions.get(owner) {
+                func.callees
+                    .iter()
+                    .chain(&func.dependencies)
+                    .collect::<Vec<_>>()
+            } else {
+                self.variables[owner].dependencies.iter().collect()
+            };
+            for dep in deps {
+                let name = dep.symbol;
+                let dep_module = some_or!(term_module(name), continue);
+                let new_name = some_or!(self.new_term_names.get(name), continue);
+                if dep_module != module {
+                    imports
+                        .entry(module)
+                        .or_default()
+                        .entry(dep_module)
+                        .or_default()
+                        .insert(new_name);
+                }
+            }
+        }
+
+        // `common` goes first so that its `macro_rules!` macros are visible in the other modules
+        let mut modules: Vec<_> = modules.into_iter().collect();
+        modules.sort_by_key(|(module, _)| *module != "common");
+        let has_macros = modules[0]
+            .1
+            .iter()
+            .any(|item| matches!(item.sort, ItemSort::Macro));
+
+        let mut files = vec![];
+        let mut main = PREAMBLE.to_string();
+        main.push('\n');
+        for (module, items) in &modules {
+            if *module == "common" && has_macros {
+                main.push_str("#[macro_use]\n");
+            }
+            main.push_str(&format!("mod {};\n", module));
+            let mut code = String::new();
+            if *module != "common" {
+                code.push_str("use super::common::*;\n");
+            }
+            for (dep_module, names) in imports.get(module).into_iter().flatten() {
+                let names: Vec<_> = names.iter().copied().collect();
+                code.push_str(&format!(
+                    "use super::{}::{{{}}};\n",
+                    dep_module,
+                    names.join(", ")
+                ));
+            }
+            for item in Self::dedup_items(items.clone()) {
+                let item = item.get_code();
+                code.push('\n');
+                code.push_str(&compiler::make_public(&item).unwrap_or(item));
+                code.push('\n');
+            }
+            files.push((format!("{}.rs", module), code));
+        }
+        main.push('\n');
+        main.push_str(&entry);
+        main.push('\n');
+        files.push(("main.rs".to_string(), main));
+        files
+    }
+
+    /// A Rust `main` that calls the translated C `main` under `prefix` and exits with its result.
+    fn main_function(&self, prefix: &str) -> String {
+        let empty = "fn main() {}".to_string();
+        let name = some_or!(self.new_term_names.get("main"), return empty);
+        let inner = self.inner.read().unwrap();
+        let translated = some_or!(inner.translated_functions.get("main"), return empty);
+        let info = translated.items.iter().find_map(|item| match &item.sort {
+            ItemSort::Function(info) if &item.name == name => Some(info),
+            _ => None,
+        });
+        let info = some_or!(info, return empty);
+        let sig = &info.signature_ty;
+
+        let mut body = vec![];
+        let mut args = vec![];
+        let argv = match &sig.params[..] {
+            [] => None,
+            [argv] => Some(argv),
+            [argc, argv] => {
+                if !is_integer(&argc.to_string()) {
+                    return empty;
+                }
+                args.push("args.len() as _".to_string());
+                Some(argv)
+            }
+            // an `envp` is not passed
+            _ => return empty,
+        };
+        if let Some(argv) = argv {
+            let argv = argv.to_string().replace(' ', "");
+            let arg = match argv.as_str() {
+                "Vec<String>" => "args.clone()",
+                "&[String]" | "&Vec<String>" => "&args",
+                "&mut[String]" | "&mutVec<String>" => "&mut args",
+                "Vec<&str>" | "&[&str]" | "&Vec<&str>" => {
+                    body.push(
+                        "let strs: Vec<&str> = args.iter().map(|s| s.as_str()).collect();"
+                            .to_string(),
+                    );
+                    if argv.starts_with('&') {
+                        "&strs"
+                    } else {
+                        "strs"
+                    }
+                }
+                _ if argv.starts_with("*mut*mut") || argv.starts_with("*const*const") => {
+                    body.push(
+                        "let cstrs: Vec<std::ffi::CString> = args.iter().map(|s| std::ffi::CString::new(s.as_str()).unwrap()).collect();"
+                            .to_string(),
+                    );
+                    body.push(
+                        "let mut ptrs: Vec<*mut libc::c_char> = cstrs.iter().map(|s| s.as_ptr() as *mut _).chain(std::iter::once(std::ptr::null_mut())).collect();"
+                            .to_string(),
+                    );
+                    "ptrs.as_mut_ptr() as _"
+                }
+                _ => return empty,
+            };
+            let m = if arg == "&mut args" { "mut " } else { "" };
+            body.insert(
+                0,
+                format!(
+                    "let {}args: Vec<String> = std::env::args_os().map(|s| s.to_string_lossy().into_owned()).collect();",
+                    m
+                ),
+            );
+            args.push(arg.to_string());
+        }
+        let call = format!("{}{}({})", prefix, name, args.join(", "));
+        let call = if info.signature.contains("unsafe ") {
+            format!("unsafe {{ {} }}", call)
+        } else {
+            call
+        };
+        let ret = sig.ret.to_string();
+        if ret == "()" || ret == "!" {
+            body.push(format!("{};", call));
+        } else if is_integer(&ret) {
+            body.push(format!("std::process::exit({} as i32);", call));
+        } else if ret.starts_with("Result<") {
+            body.push(format!(
+                "std::process::exit(if {}.is_ok() {{ 0 }} else {{ 1 }});",
+                call
+            ));
+        } else {
+            body.push(format!("let _ = {};", call));
+        }
+
+        format!("fn main() {{\n    {}\n}}", body.join("\n    "))
+    }
+
     fn make_replace_vec<'a>(
         &'a self,
         types: Option<&[TypeDependency<'a>]>,
-        vars: Option<&[&Node<Identifier>]>,
-        callees: Option<&[&Node<Identifier>]>,
+        vars: Option<&[Reference<'_>]>,
+        callees: Option<&[Reference<'_>]>,
     ) -> Vec<(Span, &'a str)> {
         let mut vec = vec![];
 
@@ -650,7 +1684,7 @@ impl<'ast> Translator<'ast> {
 
         if let Some(deps) = vars {
             for d in deps {
-                if let Some(x) = self.new_term_names.get(d.node.name.as_str()) {
+                if let Some(x) = self.new_term_names.get(d.symbol) {
                     vec.push((d.span, x.as_str()));
                 }
             }
@@ -658,7 +1692,7 @@ impl<'ast> Translator<'ast> {
 
         if let Some(deps) = callees {
             for d in deps {
-                if let Some(x) = self.new_term_names.get(d.node.name.as_str()) {
+                if let Some(x) = self.new_term_names.get(d.symbol) {
                     vec.push((d.span, x.as_str()));
                 }
             }
@@ -692,33 +1726,30 @@ impl<'ast> Translator<'ast> {
         &self,
         name: &str,
         types: Option<&[TypeDependency<'ast>]>,
-        vars: Option<&[&'ast Node<Identifier>]>,
-        funcs: Option<&[&'ast Node<Identifier>]>,
+        vars: Option<&[Reference<'ast>]>,
+        funcs: Option<&[Reference<'ast>]>,
+        macros: &[&'ast str],
+        except: Option<&'ast str>,
     ) -> DependencyPrefixes {
         tracing::info!(
-            "collect before ({})\n{:?}\n{:?}\n{:?}",
+            "collect before ({})\n{:?}\n{:?}\n{:?}\n{:?}",
             name,
             types,
             vars,
-            funcs
+            funcs,
+            macros
         );
         let mut types: Vec<_> = types.unwrap_or(&[]).iter().map(|x| x.typ).collect();
-        let mut vars: Vec<_> = vars
-            .unwrap_or(&[])
-            .iter()
-            .map(|x| x.node.name.as_str())
-            .collect();
-        let mut funcs: Vec<_> = funcs
-            .unwrap_or(&[])
-            .iter()
-            .map(|x| x.node.name.as_str())
-            .collect();
+        let mut macros = macros.to_vec();
+        let mut vars: Vec<_> = vars.unwrap_or(&[]).iter().map(|x| x.symbol).collect();
+        let mut funcs: Vec<_> = funcs.unwrap_or(&[]).iter().map(|x| x.symbol).collect();
 
         let translation_prefix: Vec<String> = {
             let mut types = types.clone();
             self.make_types_transitive(&mut types, &vars, &funcs);
             let inner = self.inner.read().unwrap();
-            let deps = Self::dedup_items(inner.collect_dependencies(&types, &vars, &funcs));
+            let deps =
+                Self::dedup_items(inner.collect_dependencies(&types, &macros, &vars, &funcs));
             deps.iter().map(|i| i.get_simple_code()).collect()
         };
         let translation_prefix = if translation_prefix
@@ -730,7 +1761,8 @@ impl<'ast> Translator<'ast> {
             translation_prefix
         } else {
             let inner = self.inner.read().unwrap();
-            let deps = Self::dedup_items(inner.collect_dependencies(&types, &vars, &funcs));
+            let deps =
+                Self::dedup_items(inner.collect_dependencies(&types, &macros, &vars, &funcs));
 
             let mut tokens = 0;
             let mut translation_prefix = vec![];
@@ -752,6 +1784,8 @@ impl<'ast> Translator<'ast> {
             .cloned()
             .collect();
         funcs.append(&mut trans);
+        // a recursive function is among its own dependencies
+        funcs.retain(|f| Some(*f) != except);
         vars.extend(
             funcs
                 .iter()
@@ -766,6 +1800,12 @@ impl<'ast> Translator<'ast> {
             .collect();
         vars.append(&mut trans);
         self.make_types_transitive(&mut types, &vars, &funcs);
+        macros.extend(
+            vars.iter()
+                .chain(&funcs)
+                .flat_map(|x| self.term_macros.get(x))
+                .flatten(),
+        );
 
         tracing::info!(
             "collect after ({})\n{:?}\n{:?}\n{:?}",
@@ -775,7 +1815,7 @@ impl<'ast> Translator<'ast> {
             funcs
         );
         let inner = self.inner.read().unwrap();
-        let deps = Self::dedup_items(inner.collect_dependencies(&types, &vars, &funcs));
+        let deps = Self::dedup_items(inner.collect_dependencies(&types, &macros, &vars, &funcs));
         let checking_prefix = std::iter::once(PREAMBLE.to_string())
             .chain(deps.iter().map(|i| i.get_checking_code()))
             .chain(std::iter::once("fn main() {}".to_string()))
@@ -889,7 +1929,14 @@ impl<'ast> Translator<'ast> {
         }
     }
 
-    async fn fix_by_llm(&self, ctxt: &mut FixContext<'_>, consider_stages: bool, is_func: bool) {
+    async fn fix_by_llm(
+        &self,
+        ctxt: &mut FixContext<'_>,
+        consider_stages: bool,
+        is_func: bool,
+        key: &str,
+        sig: Option<&str>,
+    ) {
         Self::fix_by_trait_uses(ctxt);
         let mut failed = BTreeSet::new();
         while let Some(res) = &ctxt.result {
@@ -902,9 +1949,11 @@ impl<'ast> Translator<'ast> {
                 break;
             }
             let max_len = self.config.max_tokens - code_tokens;
-            let msg_tokens: Vec<_> = res
-                .errors
-                .iter()
+            // resolution errors first, since they cause many of the errors of later stages
+            let mut errors: Vec<_> = res.errors.iter().collect();
+            errors.sort_by_key(|e| e.stage);
+            let msg_tokens: Vec<_> = errors
+                .into_iter()
                 .filter_map(|e| {
                     let msg = e.message.as_str();
                     let tokens = tokens_in_str(msg);
@@ -960,6 +2009,7 @@ impl<'ast> Translator<'ast> {
                         failed: false,
                         proto: false,
                         types: vec![],
+                        helpers: vec![],
                     }
                     .code();
                     if ctxt.code == fix {
@@ -1017,15 +2067,35 @@ impl<'ast> Translator<'ast> {
                     }
                 });
 
+            let best = successes
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (_, _, new_errors, _))| *new_errors)
+                .map(|(i, _)| i);
+            self.trail(key, |trail| {
+                let rounds = successes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| (Some(i) == best, s))
+                    .chain(failures.iter().map(|f| (false, f)));
+                for (accepted, (new_ctxt, new_stage, new_errors, msg)) in rounds {
+                    let checked = new_ctxt.is_some();
+                    trail.fixes.push(FixRound {
+                        signature: sig.map(|s| s.to_string()),
+                        error: msg.clone(),
+                        accepted,
+                        stage: checked.then_some(*new_stage),
+                        errors: checked.then_some(*new_errors),
+                    });
+                }
+            });
+
             for (_, _, _, msg) in failures {
                 failed.insert(msg);
             }
 
-            if let Some((new_ctxt, _, _, _)) = successes
-                .into_iter()
-                .min_by_key(|(_, _, new_errors, _)| *new_errors)
-            {
-                *ctxt = new_ctxt.unwrap();
+            if let Some(best) = best {
+                *ctxt = successes.into_iter().nth(best).unwrap().0.unwrap();
             } else {
                 break;
             }
@@ -1033,6 +2103,9 @@ impl<'ast> Translator<'ast> {
     }
 
     pub async fn translate_names(&mut self) {
+        if self.resumed {
+            return;
+        }
         let mut aliased_map: BTreeMap<_, Vec<_>> = BTreeMap::new();
         for ty in &self.custom_types {
             if ty.sort == TypeSort::Typedef {
@@ -1060,29 +2133,47 @@ impl<'ast> Translator<'ast> {
             .filter(|ty| !alias_set.contains(ty))
             .collect();
 
+        let mut names = Names::default();
+        let (pinned, custom_types): (Vec<&CustomType<'_>>, Vec<_>) = custom_types
+            .into_iter()
+            .partition(|ty| self.type_name_override(**ty).is_some());
+        for ty in pinned {
+            let mut new_name = self.type_name_override(*ty).unwrap();
+            // a struct and a typedef of the same name may share an override
+            if !names.reserve(&new_name) {
+                tracing::warn!("name override {} is taken", new_name);
+                new_name = names.sanitize(ty.name, &new_name, NameKind::Type);
+            }
+            self.new_type_names.insert(*ty, new_name);
+        }
+        let pinned: Vec<_> = self
+            .variables
+            .keys()
+            .chain(self.protos.keys())
+            .chain(self.functions.keys())
+            .filter_map(|name| Some((*name, self.overrides.term_name(name)?)))
+            .collect();
+        for (name, mut new_name) in pinned {
+            if !names.reserve(&new_name) {
+                tracing::warn!("name override {} is taken", new_name);
+                let kind = if self.variables.contains_key(name) {
+                    NameKind::Variable
+                } else {
+                    NameKind::Function
+                };
+                new_name = names.sanitize(name, &new_name, kind);
+            }
+            self.new_term_names.insert(name, new_name);
+        }
+
         let type_names = future::join_all(
             custom_types
                 .iter()
                 .map(|ty| self.client.rename_type(ty.name)),
         )
         .await;
-
         for (ty, new_name) in custom_types.into_iter().zip(type_names) {
-            let mut new_name = match new_name.as_str() {
-                "Option" => "MyOption",
-                "Box" => "MyBox",
-                "Copy" => "MyCopy",
-                "Cmatrix" => "Matrix",
-                "Crec" => "Rec",
-                "Crecid" => "RecId",
-                "PollLoopArgsT" => "PollLoopArgs",
-                new_name => new_name,
-            }
-            .to_string();
-            if self.new_type_names.values().any(|name| name == &new_name) {
-                new_name += "A";
-            };
-            assert!(!self.new_type_names.values().any(|name| name == &new_name));
+            let new_name = names.sanitize(ty.name, &new_name, NameKind::Type);
             self.new_type_names.insert(*ty, new_name);
         }
 
@@ -1091,40 +2182,70 @@ impl<'ast> Translator<'ast> {
             self.new_type_names.insert(struct_ty, new_name);
         }
 
+        let vars: Vec<_> = self
+            .variables
+            .keys()
+            .filter(|var| !self.new_term_names.contains_key(*var))
+            .collect();
         let var_names = future::join_all(
-            self.variables
-                .keys()
-                .map(|var| self.client.rename_variable(var)),
+            vars.iter()
+                .map(|var| self.client.rename_variable(c_parser::c_name(var))),
         )
         .await;
-        for (var, new_name) in self.variables.keys().zip(var_names) {
+        for (var, new_name) in vars.into_iter().zip(var_names) {
+            let new_name = names.sanitize(var, &new_name, NameKind::Variable);
             self.new_term_names.insert(*var, new_name);
         }
 
-        let proto_names = future::join_all(
-            self.protos
-                .keys()
-                .map(|proto| self.client.rename_function(proto)),
+        let funcs: Vec<_> = self
+            .protos
+            .keys()
+            .chain(self.functions.keys())
+            .filter(|func| !self.new_term_names.contains_key(*func))
+            .collect();
+        let func_names = future::join_all(
+            funcs
+                .iter()
+                .map(|func| self.client.rename_function(c_parser::c_name(func))),
         )
         .await;
-        for (proto, new_name) in self.protos.keys().zip(proto_names) {
-            self.new_term_names.insert(*proto, new_name);
+        for (func, new_name) in funcs.into_iter().zip(func_names) {
+            let new_name = names.sanitize(func, &new_name, NameKind::Function);
+            self.new_term_names.insert(*func, new_name);
         }
 
-        let func_names = future::join_all(
-            self.functions
-                .keys()
-                .map(|func| self.client.rename_function(func)),
-        )
+        // constants are named like variables and function-like macros like functions
+        let macros: Vec<&Macro> = self.macros.values().copied().collect();
+        let client = &self.client;
+        let macro_names = future::join_all(macros.iter().map(|m| async move {
+            if m.params.is_some() {
+                client.rename_function(&m.name).await
+            } else {
+                client.rename_variable(&m.name).await
+            }
+        }))
         .await;
-        for (func, new_name) in self.functions.keys().zip(func_names) {
-            let new_name = if KEYWORDS.contains(new_name.as_str()) {
-                format!("my_{}", new_name)
+        for (m, new_name) in macros.into_iter().zip(macro_names) {
+            let kind = if m.params.is_some() {
+                NameKind::Function
             } else {
-                new_name
+                NameKind::Variable
             };
-            self.new_term_names.insert(*func, new_name);
+            let new_name = names.sanitize(&m.name, &new_name, kind);
+            self.new_macro_names.insert(m.name.as_str(), new_name);
+        }
+
+        if !self.config.quiet {
+            for rename in names.renames() {
+                println!(
+                    "rename: {} ({} -> {}, {:?})",
+                    rename.c_name, rename.proposed, rename.new_name, rename.reason
+                );
+            }
         }
+        self.renames = names.renames().to_vec();
+
+        self.save_checkpoint();
     }
 
     async fn fix_types_after_translation(
@@ -1182,7 +2303,8 @@ impl<'ast> Translator<'ast> {
             translated_code.clone(),
             &item_names,
         );
-        self.fix_by_llm(&mut ctxt, self.config.consider_stages, false)
+        let key = format!("type {}", new_names.join(", "));
+        self.fix_by_llm(&mut ctxt, self.config.consider_stages, false, &key, None)
             .await;
         let mut failed = false;
         if !ctxt.result.as_ref().unwrap().passed() {
@@ -1240,6 +2362,7 @@ impl<'ast> Translator<'ast> {
             new_names,
             translated.code()
         );
+        self.trail(&key, |trail| trail.result = Some(translated.code()));
         if !self.config.quiet {
             println!("type: {:?}", new_names);
         }
@@ -1264,14 +2387,29 @@ impl<'ast> Translator<'ast> {
         }
     }
 
-    async fn translate_type(&self, tys: Vec<&CustomType<'_>>) -> TranslationResult {
+    async fn translate_type(&self, tys: Vec<&CustomType<'ast>>) -> TranslationResult {
         let mut all_deps = vec![];
         let mut all_code = vec![];
         let mut sorts = BTreeSet::new();
         let mut new_names = vec![];
+        let mut llm_names = vec![];
+        let mut hand_written = vec![];
+        let mut comments = vec![];
         for ty in tys {
             let new_name = self.new_type_names.get(ty).unwrap().as_str();
             new_names.push(new_name);
+            if let Some(code) = self.hand_written_type(*ty) {
+                hand_written.push(code);
+                continue;
+            }
+            llm_names.push(new_name);
+            let comment = match ty.sort {
+                TypeSort::Typedef => self.typedefs.get(ty.name).unwrap().comment,
+                TypeSort::Struct | TypeSort::Union => self.structs.get(ty.name).unwrap().comment,
+                TypeSort::Enum => self.enums.get(ty.name).unwrap().comment,
+            };
+            comments.push((new_name, comment));
+            let comment = c_comment(comment);
             match ty.sort {
                 TypeSort::Typedef => {
                     let typedef = self.typedefs.get(ty.name).unwrap();
@@ -1290,7 +2428,7 @@ impl<'ast> Translator<'ast> {
                             (self.program.typedef_to_string(typedef, vec), "type")
                         }
                     };
-                    all_code.push(code);
+                    all_code.push(format!("{}{}", comment, code));
                     sorts.insert(sort);
                 }
                 TypeSort::Struct | TypeSort::Union => {
@@ -1305,7 +2443,7 @@ impl<'ast> Translator<'ast> {
                     ));
                     let code = self.program.struct_to_string(strct, vec);
                     let sort = if strct.strct { "struct" } else { "union" };
-                    all_code.push(code);
+                    all_code.push(format!("{}{}", comment, code));
                     sorts.insert(sort);
                 }
                 TypeSort::Enum => {
@@ -1315,13 +2453,14 @@ impl<'ast> Translator<'ast> {
                         new_name,
                     )];
                     let code = self.program.enum_to_string(enm, vec);
-                    all_code.push(code);
+                    all_code.push(format!("{}{}", comment, code));
                     sorts.insert("enum");
                 }
             }
         }
 
-        let prefixes = self.collect_dependencies(new_names[0], Some(&all_deps), None, None);
+        let prefixes =
+            self.collect_dependencies(new_names[0], Some(&all_deps), None, None, &[], None);
         tracing::info!(
             "translate_type translation_prefix ({:?})\n{}",
             new_names,
@@ -1334,46 +2473,59 @@ impl<'ast> Translator<'ast> {
             new_names,
             code,
         );
+        self.trail(&format!("type {}", new_names.join(", ")), |trail| {
+            trail.c_code = code.clone();
+            trail.translation_prefix = prefixes.translation_prefix.clone();
+        });
 
         let sort = if sorts.len() == 1 {
             sorts.into_iter().next().unwrap()
         } else {
             "type"
         };
-        let translated = if tokens_in_str(&code) > self.config.max_tokens * 2 {
-            "".to_string()
-        } else {
-            self.client
-                .translate_type(&code, sort, &prefixes.translation_prefix)
-                .await
-                .unwrap_or("".to_string())
-        };
+        let translated =
+            if llm_names.is_empty() || tokens_in_str(&code) > self.config.max_tokens * 2 {
+                "".to_string()
+            } else {
+                self.client
+                    .translate_type(&code, sort, &prefixes.translation_prefix)
+                    .await
+                    .unwrap_or("".to_string())
+            };
         tracing::info!(
             "translate_type translated ({:?})\n{}",
             new_names,
             translated
         );
 
-        let items = if let Some(items) = compiler::parse(&translated) {
-            if new_names.len() == 1 && items.iter().all(|item| item.name != new_names[0]) {
+        let mut items = if llm_names.is_empty() {
+            vec![]
+        } else if let Some(items) = compiler::parse(&translated) {
+            if llm_names.len() == 1 && items.iter().all(|item| item.name != llm_names[0]) {
                 if !self.config.quiet {
-                    println!("Type not translated: {:?}", new_names);
+                    println!("Type not translated: {:?}", llm_names);
                 }
-                compiler::parse(&format!("type {} = usize;", new_names[0])).unwrap()
+                compiler::parse(&format!("type {} = usize;", llm_names[0])).unwrap()
             } else {
                 items
             }
         } else {
             if !self.config.quiet {
-                println!("Type not translated: {:?}", new_names);
+                println!("Type not translated: {:?}", llm_names);
             }
-            let types = new_names
+            let types = llm_names
                 .iter()
                 .map(|name| format!("type {} = usize;", name))
                 .collect::<Vec<_>>()
                 .join("\n");
             compiler::parse(&types).unwrap()
         };
+        items.retain(|item| {
+            llm_names.contains(&item.name.as_str()) || !new_names.contains(&item.name.as_str())
+        });
+        for code in hand_written {
+            items.extend(compiler::parse(code).into_iter().flatten());
+        }
         let translated = TranslationResult {
             items,
             stage: compiler::MAX_STAGE,
@@ -1382,9 +2534,15 @@ impl<'ast> Translator<'ast> {
             failed: false,
             proto: false,
             types: vec![],
+            helpers: vec![],
         };
-        self.fix_types_after_translation(new_names, translated, prefixes)
-            .await
+        let mut translated = self
+            .fix_types_after_translation(new_names, translated, prefixes)
+            .await;
+        for (new_name, comment) in comments {
+            document(&mut translated.items, new_name, comment);
+        }
+        translated
     }
 
     pub async fn translate_types(&self) {
@@ -1397,6 +2555,16 @@ impl<'ast> Translator<'ast> {
                 .map(|(id, _)| self.type_elem_map.get(&id).unwrap())
                 .map(|set| {
                     async {
+                        let restored = self
+                            .inner
+                            .read()
+                            .unwrap()
+                            .translated_types
+                            .get(set.first().unwrap())
+                            .cloned();
+                        if let Some(translated) = restored {
+                            return (set.iter().collect::<Vec<_>>(), translated);
+                        }
                         let (non_aliases, mut aliases): (Vec<_>, _) = set
                             .iter()
                             .map(|ty| {
@@ -1433,26 +2601,182 @@ impl<'ast> Translator<'ast> {
             let ((tys, translated), _, remaining) = future::select_all(futures).await;
             futures = remaining;
 
-            let id = self
-                .type_elem_map
+            let id = self
+                .type_elem_map
+                .iter()
+                .find_map(|(id, set)| {
+                    if tys.iter().any(|ty| set.contains(ty)) {
+                        Some(id)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap();
+            for ids in graph.values_mut() {
+                ids.remove(id);
+            }
+
+            let mut inner = self.inner.write().unwrap();
+            inner.add_names(&translated);
+            for ty in tys {
+                inner.translated_types.insert(*ty, translated.clone());
+            }
+            drop(inner);
+            self.save_checkpoint();
+        }
+    }
+
+    async fn translate_macro(&self, name: &'ast str) -> TranslationResult {
+        let new_name = self.new_macro_names.get(name).unwrap();
+        tracing::info!("translate_macro: {}", new_name);
+
+        let deps: Vec<_> = self.macro_dependencies(name).into_iter().collect();
+        let prefixes = self.collect_dependencies(new_name, None, None, None, &deps, None);
+        let names = self
+            .new_macro_names
+            .iter()
+            .map(|(name, new_name)| (*name, new_name.as_str()))
+            .collect();
+        let code = self.macros[name].to_string(&names);
+        tracing::info!("translate_macro code ({})\n{}", new_name, code);
+
+        let empty = vec![];
+        let translation_prefix = if self.config.provide_signatures {
+            &prefixes.translation_prefix
+        } else {
+            &empty
+        };
+        let key = format!("macro {}", name);
+        self.trail(&key, |trail| {
+            trail.c_code = code.clone();
+            trail.translation_prefix = translation_prefix.clone();
+        });
+
+        // a macro that cannot be translated is left out, and its uses stay expanded
+        let on_failure = || {
+            if !self.config.quiet {
+                println!("Macro not translated: {}", new_name);
+            }
+            TranslationResult {
+                items: vec![],
+                stage: Stage::default(),
+                errors: 0,
+                too_long: false,
+                failed: true,
+                proto: false,
+                types: vec![],
+                helpers: vec![],
+            }
+        };
+
+        let translated = some_or!(
+            self.client.translate_macro(&code, translation_prefix).await,
+            return on_failure()
+        );
+        tracing::info!("translate_macro translated ({})\n{}", new_name, translated);
+        let mut items = some_or!(compiler::parse(&translated), return on_failure());
+        let uses = Self::take_uses(&mut items);
+        items.retain(|item| {
+            matches!(
+                item.sort,
+                ItemSort::Variable(_) | ItemSort::Function(_) | ItemSort::Macro
+            )
+        });
+        let item = if let Some(i) = items.iter().position(|item| item.name == *new_name) {
+            items.swap_remove(i)
+        } else {
+            let item = some_or!(
+                items
+                    .into_iter()
+                    .find(|item| !matches!(item.sort, ItemSort::Macro)),
+                return on_failure()
+            );
+            let code = some_or!(
+                compiler::rename_item(&item.get_code(), new_name),
+                return on_failure()
+            );
+            some_or!(compiler::parse_one(&code), return on_failure())
+        };
+
+        let translated = item.get_code();
+        let translated = compiler::resolve_imports(&translated, &uses.join("")).unwrap();
+        let translated =
+            compiler::resolve_free_types(&translated, &prefixes.checking_prefix, self.config.quiet)
+                .unwrap();
+        let item_names = BTreeSet::from([new_name.clone()]);
+        let mut ctxt = FixContext::new(&prefixes.checking_prefix, translated, &item_names);
+        if self.config.fix_errors {
+            self.fix_by_llm(&mut ctxt, self.config.consider_stages, false, &key, None)
+                .await;
+        }
+        let res = some_or!(ctxt.result.as_ref(), return on_failure());
+        let mut items = some_or!(compiler::parse(&ctxt.code), return on_failure());
+        for item in &mut items {
+            if matches!(item.sort, ItemSort::Function(_)) {
+                item.code = format!("#[inline]\n{}", item.code);
+            }
+        }
+        let translated = TranslationResult {
+            items,
+            stage: res.stage,
+            errors: res.errors.len(),
+            too_long: false,
+            failed: false,
+            proto: false,
+            types: vec![],
+            helpers: vec![],
+        };
+
+        tracing::info!(
+            "translate_macro result ({})\n{}",
+            new_name,
+            translated.code()
+        );
+        self.trail(&key, |trail| trail.result = Some(translated.code()));
+        if !self.config.quiet {
+            println!(
+                "macro: {} ({}, {})",
+                new_name, translated.stage, translated.errors
+            );
+        }
+
+        translated
+    }
+
+    pub async fn translate_macros(&self) {
+        let mut remaining: BTreeSet<_> = {
+            let inner = self.inner.read().unwrap();
+            self.new_macro_names
+                .keys()
+                .copied()
+                .filter(|name| !inner.translated_macros.contains_key(name))
+                .collect()
+        };
+        while !remaining.is_empty() {
+            let mut ready: Vec<_> = remaining
                 .iter()
-                .find_map(|(id, set)| {
-                    if tys.iter().any(|ty| set.contains(ty)) {
-                        Some(id)
-                    } else {
-                        None
-                    }
+                .copied()
+                .filter(|name| {
+                    self.macro_dependencies(name)
+                        .iter()
+                        .all(|dep| !remaining.contains(dep))
                 })
-                .unwrap();
-            for ids in graph.values_mut() {
-                ids.remove(id);
+                .collect();
+            // macros expanding to each other are translated without each other
+            if ready.is_empty() {
+                ready = remaining.iter().copied().collect();
             }
+            let translated =
+                future::join_all(ready.iter().map(|name| self.translate_macro(name))).await;
 
             let mut inner = self.inner.write().unwrap();
-            inner.add_names(&translated);
-            for ty in tys {
-                inner.translated_types.insert(*ty, translated.clone());
+            for (name, translated) in ready.into_iter().zip(translated) {
+                remaining.remove(name);
+                inner.add_names(&translated);
+                inner.translated_macros.insert(name, translated);
             }
+            drop(inner);
+            self.save_checkpoint();
         }
     }
 
@@ -1463,10 +2787,29 @@ impl<'ast> Translator<'ast> {
 
         let tdeps = &var.type_dependencies;
         let deps = &var.dependencies;
-        let prefixes = self.collect_dependencies(new_name, Some(tdeps), Some(deps), None);
+        let macros = self.used_macros(name);
+        let prefixes =
+            self.collect_dependencies(new_name, Some(tdeps), Some(deps), None, &macros, None);
+
+        let hand_written = self.overrides.variables.get(name);
+        if let Some(translated) =
+            hand_written.and_then(|code| self.hand_written(code, &prefixes.checking_prefix))
+        {
+            self.trail(&format!("variable {}", name), |trail| {
+                trail.result = Some(translated.code())
+            });
+            if !self.config.quiet {
+                println!(
+                    "variable: {} ({}, {}, hand-written)",
+                    new_name, translated.stage, translated.errors
+                );
+            }
+            return translated;
+        }
 
         let mut vec = self.make_replace_vec(Some(tdeps), Some(deps), None);
         vec.push((var.identifier.span, new_name));
+        self.push_constants(name, &mut vec);
         let code = self.program.variable_to_string(var, vec.clone(), false);
         let too_long = tokens_in_str(&code) > self.config.max_tokens;
         let code = if too_long {
@@ -1474,6 +2817,12 @@ impl<'ast> Translator<'ast> {
         } else {
             code
         };
+        let code = format!(
+            "{}{}{}",
+            self.macro_definitions(&macros),
+            c_comment(var.comment),
+            code
+        );
         tracing::info!(
             "translate_variable code ({})\ntoo_long: {}\n{}",
             new_name,
@@ -1492,6 +2841,11 @@ impl<'ast> Translator<'ast> {
             new_name,
             translation_prefix.join("\n")
         );
+        let key = format!("variable {}", name);
+        self.trail(&key, |trail| {
+            trail.c_code = code.clone();
+            trail.translation_prefix = translation_prefix.clone();
+        });
 
         let on_failure = || {
             if !self.config.quiet {
@@ -1554,6 +2908,7 @@ impl<'ast> Translator<'ast> {
             failed: false,
             proto: false,
             types: vec![],
+            helpers: vec![],
         };
         tracing::info!(
             "translate_variable translated ({})\n{}",
@@ -1574,7 +2929,7 @@ impl<'ast> Translator<'ast> {
             &item_names,
         );
         if self.config.fix_errors {
-            self.fix_by_llm(&mut ctxt, self.config.consider_stages, false)
+            self.fix_by_llm(&mut ctxt, self.config.consider_stages, false, &key, None)
                 .await;
             if translated_code != ctxt.code {
                 let fixed_items = compiler::parse(&ctxt.code).unwrap();
@@ -1608,12 +2963,14 @@ impl<'ast> Translator<'ast> {
         if translated.code().ends_with(":usize=0;") {
             translated.failed = true;
         }
+        document(&mut translated.items, new_name, var.comment);
 
         tracing::info!(
             "translate_variable result ({})\n{}",
             new_name,
             translated.code()
         );
+        self.trail(&key, |trail| trail.result = Some(translated.code()));
         for e in &ctxt.result.unwrap().errors {
             tracing::info!("translate_variable error ({})\n{}", new_name, e.message);
         }
@@ -1638,7 +2995,16 @@ impl<'ast> Translator<'ast> {
                 .map(|set| {
                     async {
                         let var = *set.first().unwrap();
-                        let translated = if set.len() == 1 {
+                        let restored = self
+                            .inner
+                            .read()
+                            .unwrap()
+                            .translated_variables
+                            .get(var)
+                            .cloned();
+                        let translated = if let Some(translated) = restored {
+                            translated
+                        } else if set.len() == 1 {
                             self.translate_variable(var).await
                         } else {
                             let code = set
@@ -1655,6 +3021,7 @@ impl<'ast> Translator<'ast> {
                                 failed: false,
                                 proto: false,
                                 types: vec![],
+                                helpers: vec![],
                             }
                         };
                         (var, translated)
@@ -1683,6 +3050,8 @@ impl<'ast> Translator<'ast> {
             let mut inner = self.inner.write().unwrap();
             inner.add_names(&translated);
             inner.translated_variables.insert(var, translated);
+            drop(inner);
+            self.save_checkpoint();
         }
     }
 
@@ -1698,7 +3067,8 @@ impl<'ast> Translator<'ast> {
         let code = self.program.variable_to_string(proto, vec, false);
         tracing::info!("translate_proto code ({})\n{}", new_name, code);
 
-        let prefixes = self.collect_dependencies(new_name, Some(tdeps), Some(deps), None);
+        let prefixes =
+            self.collect_dependencies(new_name, Some(tdeps), Some(deps), None, &[], None);
         let empty = vec![];
         let translation_prefix = if self.config.provide_signatures {
             &prefixes.translation_prefix
@@ -1716,19 +3086,43 @@ impl<'ast> Translator<'ast> {
             prefixes.checking_prefix
         );
 
-        let sig_map = self.translate_signature(&code, new_name, &prefixes).await;
-        let sig = sig_map
-            .into_iter()
-            .next()
-            .map(|(_, sig)| sig)
-            .unwrap_or_else(|| {
-                if !self.config.quiet {
-                    println!("Proto not translated: {}", new_name);
-                }
-                format!("fn {}()", new_name)
-            });
+        let key = format!("proto {}", name);
+        self.trail(&key, |trail| {
+            trail.c_code = code.clone();
+            trail.translation_prefix = translation_prefix.clone();
+        });
+
+        let hand_written = self.overrides.functions.get(name);
+        if let Some(translated) = hand_written
+            .and_then(|code| self.hand_written_function(code, &prefixes.checking_prefix))
+        {
+            self.trail(&key, |trail| trail.result = Some(translated.code()));
+            if !self.config.quiet {
+                println!("proto: {} (hand-written)", new_name);
+            }
+            return translated;
+        }
+
+        let sig = if let Some(info) = self.overrides.signature(name, new_name) {
+            info.signature
+        } else {
+            let sig_map = self
+                .translate_signature(&key, &code, new_name, &prefixes)
+                .await;
+            sig_map
+                .into_iter()
+                .next()
+                .map(|(_, sig)| sig)
+                .unwrap_or_else(|| {
+                    if !self.config.quiet {
+                        println!("Proto not translated: {}", new_name);
+                    }
+                    format!("fn {}()", new_name)
+                })
+        };
         let translated = format!("{}{{todo!(\"proto\")}}", sig);
         tracing::info!("translate_proto result ({})\n{}", new_name, translated);
+        self.trail(&key, |trail| trail.result = Some(translated.clone()));
 
         if !self.config.quiet {
             println!("proto: {}", new_name);
@@ -1741,13 +3135,21 @@ impl<'ast> Translator<'ast> {
             failed: false,
             proto: true,
             types: vec![],
+            helpers: vec![],
         }
     }
 
     pub async fn translate_protos(&self) {
-        let translated = future::join_all(
+        let protos: Vec<_> = {
+            let inner = self.inner.read().unwrap();
             self.protos
                 .keys()
+                .filter(|name| !inner.translated_functions.contains_key(*name))
+                .collect()
+        };
+        let translated = future::join_all(
+            protos
+                .into_iter()
                 .map(|name| async { (*name, self.translate_proto(name).await) }.boxed()),
         )
         .await;
@@ -1756,6 +3158,7 @@ impl<'ast> Translator<'ast> {
             inner.add_names(&translated);
             inner.translated_functions.insert(name, translated);
         }
+        self.save_checkpoint();
     }
 
     async fn translate_function(
@@ -1779,6 +3182,7 @@ impl<'ast> Translator<'ast> {
             }
         }
         vec.push((func.identifier.span, new_name));
+        self.push_constants(name, &mut vec);
         let code = self.program.function_to_string(func, vec.clone());
         let too_long = tokens_in_str(&code) > self.config.max_tokens;
         let code = if too_long {
@@ -1786,6 +3190,13 @@ impl<'ast> Translator<'ast> {
         } else {
             code
         };
+        let macros = self.used_macros(name);
+        let code = format!(
+            "{}{}{}",
+            self.macro_definitions(&macros),
+            c_comment(func.comment),
+            code
+        );
         tracing::info!(
             "translate_function code ({})\ntoo_long: {}\n{}",
             new_name,
@@ -1793,7 +3204,14 @@ impl<'ast> Translator<'ast> {
             code
         );
 
-        let prefixes = self.collect_dependencies(new_name, Some(tdeps), Some(deps), Some(callees));
+        let prefixes = self.collect_dependencies(
+            new_name,
+            Some(tdeps),
+            Some(deps),
+            Some(callees),
+            &macros,
+            None,
+        );
         tracing::info!(
             "translate_function translation_prefix ({})\n{}",
             new_name,
@@ -1804,9 +3222,29 @@ impl<'ast> Translator<'ast> {
             new_name,
             prefixes.checking_prefix
         );
+        let key = format!("function {}", name);
+        self.trail(&key, |trail| {
+            trail.c_code = code.clone();
+            trail.translation_prefix = prefixes.translation_prefix.clone();
+        });
+
+        let hand_written = self.overrides.functions.get(name);
+        if let Some(translated) = hand_written
+            .and_then(|code| self.hand_written_function(code, &prefixes.checking_prefix))
+        {
+            self.trail(&key, |trail| trail.result = Some(translated.code()));
+            if !self.config.quiet {
+                println!(
+                    "function: {} ({}, {}, hand-written)",
+                    new_name, translated.stage, translated.errors
+                );
+            }
+            return translated;
+        }
 
+        let pinned_sig = self.overrides.signature(name, new_name);
+        let target_sig = target_sig.or(pinned_sig.as_ref());
         let translated = if let Some(target_sig) = target_sig {
-            assert!(prev.is_some());
             let translated = self
                 .try_signature(
                     Some(&target_sig.signature),
@@ -1818,14 +3256,18 @@ impl<'ast> Translator<'ast> {
                     true,
                 )
                 .await
-                .unwrap_or(prev.unwrap());
-            assert_eq!(translated.items.len(), 1);
-            let item = &translated.items[0];
-            let f = item.as_function().unwrap();
-            assert_eq!(target_sig.signature, f.signature);
-            Some(translated)
+                .or(prev);
+            if let Some(translated) = &translated {
+                assert_eq!(translated.items.len(), 1);
+                let item = &translated.items[0];
+                let f = item.as_function().unwrap();
+                assert_eq!(target_sig.signature, f.signature);
+            }
+            translated
         } else if self.config.try_multiple_signatures {
-            let mut sig_map = self.translate_signature(&code, new_name, &prefixes).await;
+            let mut sig_map = self
+                .translate_signature(&key, &code, new_name, &prefixes)
+                .await;
             if sig_map.is_empty() {
                 if !self.config.quiet {
                     println!("Signature not translated: {}", new_name);
@@ -1857,12 +3299,11 @@ impl<'ast> Translator<'ast> {
                     None
                 } else {
                     if self.config.consider_stages {
-                        let (neg_max_stage, min_errors) = candidates
+                        let (Reverse(max_stage), min_errors) = candidates
                             .iter()
-                            .map(|c| (-(c.stage as isize), c.errors))
+                            .map(|c| (Reverse(c.stage), c.errors))
                             .min()
                             .expect(new_name);
-                        let max_stage = -neg_max_stage as usize;
                         candidates.retain(|c| c.stage == max_stage && c.errors == min_errors);
                     } else {
                         let min_errors = candidates.iter().map(|c| c.errors).min().expect(new_name);
@@ -1880,9 +3321,19 @@ impl<'ast> Translator<'ast> {
                     candidates.reverse();
                     let mut best = candidates.pop().unwrap();
                     while let Some(cand) = candidates.pop() {
-                        if self.client.compare(&best.code(), &cand.code()).await
-                            == std::cmp::Ordering::Less
-                        {
+                        let candidate_won = self.client.compare(&best.code(), &cand.code()).await
+                            == std::cmp::Ordering::Less;
+                        self.trail(&key, |trail| {
+                            let sig = |t: &TranslationResult| {
+                                t.items[0].as_function().unwrap().signature.clone()
+                            };
+                            trail.tournament.push(Comparison {
+                                best: sig(&best),
+                                candidate: sig(&cand),
+                                candidate_won,
+                            });
+                        });
+                        if candidate_won {
                             best = cand;
                         }
                     }
@@ -1897,7 +3348,10 @@ impl<'ast> Translator<'ast> {
             if !self.config.quiet {
                 println!("Function not translated: {}", new_name);
             }
-            let code = format!("fn {}() {{todo!()}}", new_name);
+            let code = match target_sig {
+                Some(target_sig) => format!("{}{{todo!()}}", target_sig.signature),
+                None => format!("fn {}() {{todo!()}}", new_name),
+            };
             let items = compiler::parse(&code).unwrap();
             TranslationResult {
                 items,
@@ -1907,6 +3361,7 @@ impl<'ast> Translator<'ast> {
                 failed: true,
                 proto: false,
                 types: vec![],
+                helpers: vec![],
             }
         });
         translated.too_long = too_long;
@@ -1914,12 +3369,15 @@ impl<'ast> Translator<'ast> {
         assert_eq!(translated.items.len(), 1);
         let types = compiler::get_types(&translated.items[0].code).unwrap();
         translated.types = types;
+        document(&mut translated.items, new_name, func.comment);
+        self.record_missing_functions(name, &translated, &prefixes.checking_prefix);
 
         tracing::info!(
             "translate_function result ({})\n{}",
             new_name,
             translated.code()
         );
+        self.trail(&key, |trail| trail.result = Some(translated.code()));
 
         if !self.config.quiet {
             println!(
@@ -1932,6 +3390,7 @@ impl<'ast> Translator<'ast> {
 
     async fn translate_signature(
         &self,
+        key: &str,
         code: &str,
         new_name: &str,
         prefixes: &DependencyPrefixes,
@@ -1952,6 +3411,7 @@ impl<'ast> Translator<'ast> {
             )
             .await;
         tracing::info!("translate_signature ({})\n{}", new_name, sigs.join("\n"));
+        self.trail(key, |trail| trail.proposed_signatures = sigs.clone());
 
         let mut sig_map = BTreeMap::new();
         for sig in sigs {
@@ -1996,6 +3456,43 @@ impl<'ast> Translator<'ast> {
         prefixes: &DependencyPrefixes,
         too_long: bool,
         must_preserve: bool,
+    ) -> Option<TranslationResult> {
+        let key = format!("function {}", name);
+        let translated = self
+            .try_signature_inner(
+                &key,
+                sig,
+                name,
+                new_name,
+                code,
+                prefixes,
+                too_long,
+                must_preserve,
+            )
+            .await;
+        self.trail(&key, |trail| {
+            trail.signatures.push(SignatureTrial {
+                signature: sig.map(|s| s.to_string()),
+                translated: translated.is_some(),
+                stage: translated.as_ref().map(|t| t.stage),
+                errors: translated.as_ref().map(|t| t.errors),
+                code: translated.as_ref().map(|t| t.code()),
+            })
+        });
+        translated
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn try_signature_inner(
+        &self,
+        key: &str,
+        sig: Option<&str>,
+        name: &str,
+        new_name: &str,
+        code: &str,
+        prefixes: &DependencyPrefixes,
+        too_long: bool,
+        must_preserve: bool,
     ) -> Option<TranslationResult> {
         let empty = vec![];
         let translation_prefix = if self.config.provide_signatures && !too_long {
@@ -2011,18 +3508,21 @@ impl<'ast> Translator<'ast> {
         let mut items = compiler::parse(&translated)?;
         let uses = Self::take_uses(&mut items);
         items.retain(|item| matches!(item.sort, ItemSort::Function(_)));
-        let item = if items.iter().any(|item| item.name == *new_name) {
-            items
-                .into_iter()
-                .find(|item| item.name == *new_name)
-                .unwrap()
+        let item = if let Some(i) = items.iter().position(|item| item.name == *new_name) {
+            items.remove(i)
         } else {
             let item = items.pop()?;
             let translated = compiler::rename_item(&item.get_code(), new_name).unwrap();
             compiler::parse(&translated).unwrap().pop().unwrap()
         };
+        // functions the model added, e.g. for `goto` labels, rather than other translated ones
+        let (helpers, helper_names): (Vec<_>, Vec<_>) = items
+            .iter()
+            .filter(|item| !self.new_term_names.values().any(|name| *name == item.name))
+            .map(|item| (item.get_code(), item.name.clone()))
+            .unzip();
 
-        let translated = item.get_code();
+        let translated = compiler::nest_functions(&item.get_code(), &helpers)?;
         let translated = compiler::rename_params(&translated).unwrap();
         let translated = compiler::resolve_imports(&translated, &uses.join("")).unwrap();
         let item = compiler::parse_one(&translated).unwrap();
@@ -2033,6 +3533,13 @@ impl<'ast> Translator<'ast> {
             self.config.quiet,
         )
         .unwrap();
+        let translated = compiler::resolve_free_functions(
+            &translated,
+            &prefixes.checking_prefix,
+            &self.callee_names(name),
+            self.config.quiet,
+        )
+        .unwrap();
         let item = compiler::parse_one(&translated).unwrap();
         if let Some(sig) = sig {
             if must_preserve && item.as_function().unwrap().signature != sig {
@@ -2050,6 +3557,7 @@ impl<'ast> Translator<'ast> {
             failed: false,
             proto: false,
             types: vec![],
+            helpers: helper_names,
         };
         tracing::info!(
             "try_signature translated ({})\n{}\n{}",
@@ -2069,7 +3577,7 @@ impl<'ast> Translator<'ast> {
         translated.errors = res.errors.len();
 
         if self.config.fix_errors {
-            self.fix_by_llm(&mut ctxt, self.config.consider_stages, true)
+            self.fix_by_llm(&mut ctxt, self.config.consider_stages, true, key, sig)
                 .await;
             if translated_code != ctxt.code {
                 let fixed_items = compiler::parse(&ctxt.code).expect(&ctxt.code);
@@ -2126,6 +3634,12 @@ impl<'ast> Translator<'ast> {
     }
 
     async fn translate_recursive_functions(&self, names: BTreeSet<&'ast str>) {
+        if names
+            .iter()
+            .all(|name| self.inner.read().unwrap().finished_functions.contains(name))
+        {
+            return;
+        }
         if !self.config.quiet && names.len() > 1 {
             println!("{:?}", names);
         }
@@ -2137,7 +3651,7 @@ impl<'ast> Translator<'ast> {
                 let callees = func
                     .callees
                     .iter()
-                    .map(|callee| callee.node.name.as_str())
+                    .map(|callee| callee.symbol)
                     .filter(|callee| names.contains(callee) && callee != name)
                     .collect();
                 (*name, callees)
@@ -2174,6 +3688,9 @@ impl<'ast> Translator<'ast> {
                 inner.translated_functions.insert(name, translated);
             }
         }
+
+        let mut inner = self.inner.write().unwrap();
+        inner.finished_functions.extend(names);
     }
 
     fn remove_func(&self, name: &str) -> Option<TranslationResult> {
@@ -2232,6 +3749,7 @@ impl<'ast> Translator<'ast> {
 
             let (func, _, remaining) = future::select_all(futures).await;
             futures = remaining;
+            self.save_checkpoint();
 
             let id = self
                 .function_elem_map
@@ -2243,6 +3761,287 @@ impl<'ast> Translator<'ast> {
             }
         }
     }
+
+    /// Translates the SCC of `item`, a C or Rust name, again and type checks its callers.
+    pub async fn retranslate(&self, item: &str) {
+        let name = self
+            .functions
+            .get_key_value(item)
+            .map(|(name, _)| *name)
+            .or_else(|| {
+                self.new_term_names
+                    .iter()
+                    .find(|(name, new_name)| {
+                        *new_name == item && self.functions.contains_key(*name)
+                    })
+                    .map(|(name, _)| *name)
+            })
+            .unwrap_or_else(|| panic!("unknown function: {}", item));
+        let names: BTreeSet<_> = self
+            .function_elem_map
+            .values()
+            .find(|set| set.contains(name))
+            .unwrap()
+            .iter()
+            .copied()
+            .filter(|f| self.functions.contains_key(f))
+            .collect();
+
+        let old_sigs = self.signatures(&names);
+        {
+            let mut inner = self.inner.write().unwrap();
+            inner.finished_functions.retain(|f| !names.contains(f));
+            inner.unsafe_counts.retain(|f, _| !names.contains(f));
+        }
+        self.translate_recursive_functions(names.clone()).await;
+        self.save_checkpoint();
+
+        if old_sigs == self.signatures(&names) {
+            return;
+        }
+        let callers: Vec<_> = self
+            .transitive_functions
+            .iter()
+            .filter(|(caller, callees)| {
+                !names.contains(*caller)
+                    && self.functions.contains_key(*caller)
+                    && callees.iter().any(|callee| names.contains(callee))
+            })
+            .map(|(caller, _)| *caller)
+            .collect();
+        for caller in callers {
+            let func = self.functions.get(caller).unwrap();
+            let new_name = self.new_term_names.get(caller).unwrap();
+            let prefixes = self.collect_dependencies(
+                new_name,
+                Some(&func.type_dependencies),
+                Some(&func.dependencies),
+                Some(&func.callees),
+                &self.used_macros(caller),
+                None,
+            );
+            let translated = some_or!(
+                self.inner
+                    .read()
+                    .unwrap()
+                    .translated_functions
+                    .get(caller)
+                    .cloned(),
+                continue
+            );
+            let item_names = translated.items.iter().map(|i| i.name.clone()).collect();
+            let ctxt = FixContext::new(&prefixes.checking_prefix, translated.code(), &item_names);
+            let res = some_or!(ctxt.result, continue);
+            let (stage, errors) = (res.stage, res.errors.len());
+            if !self.config.quiet && (stage, errors) != (translated.stage, translated.errors) {
+                println!(
+                    "caller: {} ({}, {}) -> ({}, {})",
+                    new_name, translated.stage, translated.errors, stage, errors
+                );
+            }
+            let mut inner = self.inner.write().unwrap();
+            let translated = inner.translated_functions.get_mut(caller).unwrap();
+            translated.stage = stage;
+            translated.errors = errors;
+        }
+        self.save_checkpoint();
+    }
+
+    /// Keeps the rewrites by the model that type-check and have less unsafe code.
+    pub async fn reduce_unsafe(&self) {
+        let names: Vec<_> = {
+            let inner = self.inner.read().unwrap();
+            self.functions
+                .keys()
+                .filter(|name| {
+                    inner.translated_functions.contains_key(*name)
+                        && !inner.unsafe_counts.contains_key(*name)
+                })
+                .copied()
+                .collect()
+        };
+        let futures = names.into_iter().map(|name| async move {
+            let reduced = self.reduce_unsafe_function(name).await;
+            (name, reduced)
+        });
+        for (name, reduced) in future::join_all(futures).await {
+            let (reduction, translated) = some_or!(reduced, continue);
+            let mut inner = self.inner.write().unwrap();
+            inner.unsafe_counts.insert(name, reduction);
+            if let Some(translated) = translated {
+                inner.translated_functions.insert(name, translated);
+            }
+        }
+        self.save_checkpoint();
+    }
+
+    async fn reduce_unsafe_function(
+        &self,
+        name: &'ast str,
+    ) -> Option<(UnsafeReduction, Option<TranslationResult>)> {
+        let func = self.functions.get(name).unwrap();
+        let new_name = self.new_term_names.get(name).unwrap();
+        let translated = self
+            .inner
+            .read()
+            .unwrap()
+            .translated_functions
+            .get(name)
+            .cloned()?;
+        if translated.failed || translated.proto {
+            return None;
+        }
+        let item = translated.items.iter().find(|i| i.name == *new_name)?;
+        let sig = &item.as_function()?.signature_ty;
+
+        let prefixes = self.collect_dependencies(
+            new_name,
+            Some(&func.type_dependencies),
+            Some(&func.dependencies),
+            Some(&func.callees),
+            &self.used_macros(name),
+            Some(name),
+        );
+        let item_names = BTreeSet::from([new_name.clone()]);
+        let ctxt = FixContext::new(&prefixes.checking_prefix, translated.code(), &item_names);
+        let before = *compiler::count_unsafe(&ctxt.code())?.get(new_name)?;
+        let unchanged = Some((
+            UnsafeReduction {
+                before,
+                after: before,
+            },
+            None,
+        ));
+        if before.total() == 0
+            || translated.errors > 0
+            || self.overrides.functions.contains_key(name)
+        {
+            return unchanged;
+        }
+
+        let (doc, code) = compiler::split_doc(&item.code);
+        let translation_prefix = if self.config.provide_signatures {
+            &prefixes.translation_prefix[..]
+        } else {
+            &[]
+        };
+        let rewritten = some_or!(
+            self.client.remove_unsafe(code, translation_prefix).await,
+            return unchanged
+        );
+        tracing::info!("reduce_unsafe rewritten ({})\n{}", new_name, rewritten);
+        let mut items = some_or!(compiler::parse(&rewritten), return unchanged);
+        let uses = Self::take_uses(&mut items);
+        let item =
+            some_or!(
+                items.into_iter().find(|i| i.name == *new_name
+                    && i.as_function().map(|f| &f.signature_ty) == Some(sig)),
+                return unchanged
+            );
+        let rewritten = compiler::resolve_imports(&item.code, &uses.join("")).unwrap();
+        let ctxt = FixContext::new(&prefixes.checking_prefix, rewritten, &item_names);
+        let res = some_or!(ctxt.result.as_ref(), return unchanged);
+        if !res.passed() {
+            return unchanged;
+        }
+        let after = *some_or!(
+            compiler::count_unsafe(&ctxt.code())
+                .as_ref()
+                .and_then(|counts| counts.get(new_name)),
+            return unchanged
+        );
+        if after.total() >= before.total() {
+            return unchanged;
+        }
+
+        let mut items = some_or!(compiler::parse(&ctxt.code), return unchanged);
+        for item in &mut items {
+            item.code = format!("{}{}", doc, item.code);
+        }
+        let types = compiler::get_types(&items[0].code).unwrap_or_default();
+        let rewritten = TranslationResult {
+            items,
+            stage: res.stage,
+            errors: 0,
+            types,
+            ..translated
+        };
+        if !self.config.quiet {
+            println!(
+                "unsafe: {} ({} -> {})",
+                new_name,
+                before.total(),
+                after.total()
+            );
+        }
+        Some((UnsafeReduction { before, after }, Some(rewritten)))
+    }
+
+    /// The new names of the functions that `name` calls.
+    fn callee_names(&self, name: &str) -> Vec<&str> {
+        self.functions[name]
+            .callees
+            .iter()
+            .filter_map(|callee| self.new_term_names.get(callee.symbol))
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    fn record_missing_functions(
+        &self,
+        name: &str,
+        translated: &TranslationResult,
+        checking_prefix: &str,
+    ) {
+        let name = self.term_name(name);
+        let missing = if translated.errors > 0 && !translated.failed {
+            let code = format!("{}\n{}", checking_prefix, translated.code());
+            compiler::free_functions(&code).unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let mut inner = self.inner.write().unwrap();
+        if missing.is_empty() {
+            inner.missing_functions.remove(name);
+        } else {
+            if !self.config.quiet {
+                let new_name = &self.new_term_names[name];
+                println!("missing functions: {} ({})", new_name, missing.join(", "));
+            }
+            inner.missing_functions.insert(name, missing);
+        }
+    }
+
+    fn signatures(&self, names: &BTreeSet<&'ast str>) -> Vec<Option<String>> {
+        let inner = self.inner.read().unwrap();
+        names
+            .iter()
+            .map(|name| {
+                let translated = inner.translated_functions.get(name)?;
+                let f = translated.items.first()?.as_function()?;
+                Some(f.signature.clone())
+            })
+            .collect()
+    }
+}
+
+fn c_comment(comment: Option<&str>) -> String {
+    comment
+        .into_iter()
+        .flat_map(|comment| comment.lines())
+        .map(|line| format!("// {}\n", line).replace(" \n", "\n"))
+        .collect()
+}
+
+/// Puts a comment of the C code before the translated item named `name` as a doc comment.
+fn document(items: &mut [ParsedItem], name: &str, comment: Option<&str>) {
+    let comment = some_or!(comment, return);
+    let item = some_or!(items.iter_mut().find(|item| item.name == name), return);
+    let doc: String = comment
+        .lines()
+        .map(|line| format!("/// {}\n", line).replace(" \n", "\n"))
+        .collect();
+    item.code = format!("{}{}", doc, item.code);
 }
 
 fn difference(s1: &str, s2: &str) -> String {
@@ -2261,8 +4060,245 @@ fn difference(s1: &str, s2: &str) -> String {
     result
 }
 
-lazy_static! {
-    static ref KEYWORDS: BTreeSet<&'static str> = KEYWORDS_RAW.iter().copied().collect();
+fn is_integer(ty: &str) -> bool {
+    matches!(
+        differential::scalar_kind(ty),
+        Some(Scalar::Signed | Scalar::Unsigned)
+    )
+}
+
+fn module_name(stem: &str) -> String {
+    let name: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name
+    } else {
+        format!("m_{}", name)
+    }
 }
 
-static KEYWORDS_RAW: [&str; 7] = ["main", "loop", "match", "where", "mod", "ref", "return"];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockModel, TempPath};
+
+    fn config() -> Config {
+        Config {
+            max_tokens: 4000,
+            try_multiple_signatures: false,
+            num_signatures: 1,
+            provide_signatures: true,
+            fix_errors: false,
+            consider_stages: false,
+            quiet: true,
+        }
+    }
+
+    async fn translate(translator: &mut Translator<'_>) {
+        translator.translate_names().await;
+        translator.translate_types().await;
+        translator.translate_macros().await;
+        translator.translate_variables().await;
+        translator.translate_protos().await;
+        translator.translate_functions().await;
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let program = Program::from_code("int factor = 2; int twice(int x) { return x * factor; }");
+        let path = TempPath::new("resume.json");
+        let model = MockModel {
+            variable: Some("static FACTOR: i32 = 2;".to_string()),
+            function: Some("fn twice(x: i32) -> i32 { x * FACTOR }".to_string()),
+            ..Default::default()
+        };
+
+        let mut translator = Translator::new(&program, Box::new(model), config());
+        translator.set_checkpoint(path.as_str().to_string());
+        translate(&mut translator).await;
+        let code = translator.code();
+        assert!(code.contains("fn twice(x: i32) -> i32"), "{}", code);
+
+        let mut translator = Translator::new(&program, Box::new(MockModel::offline()), config());
+        translator.set_checkpoint(path.as_str().to_string());
+        assert!(translator.resume());
+        translate(&mut translator).await;
+        assert_eq!(translator.new_term_names["factor"], "FACTOR");
+        assert_eq!(translator.code(), code);
+    }
+
+    #[tokio::test]
+    async fn test_hand_written_uses() {
+        let program = Program::from_code("int larger(int a, int b) { return a > b ? a : b; }");
+        let mut translator = Translator::new(&program, Box::<MockModel>::default(), config());
+        translator
+            .set_overrides(Overrides {
+                functions: BTreeMap::from([(
+                    "larger".to_string(),
+                    "use std::cmp::max;\nfn larger(a: i32, b: i32) -> i32 { max(a, b) }"
+                        .to_string(),
+                )]),
+                ..Default::default()
+            })
+            .unwrap();
+        translate(&mut translator).await;
+        let code = translator.code();
+        assert!(!code.contains("use "), "{}", code);
+        assert!(code.contains("std::cmp::max(a, b)"), "{}", code);
+    }
+
+    /// The translation of a C `main` overridden by `main`, with its Rust entry point.
+    async fn entry_point(main: &str) -> String {
+        let program =
+            Program::from_code("int main(int argc, char **argv, char **envp) { return argc; }");
+        let mut translator = Translator::new(&program, Box::<MockModel>::default(), config());
+        translator
+            .set_overrides(Overrides {
+                functions: BTreeMap::from([("main".to_string(), main.to_string())]),
+                ..Default::default()
+            })
+            .unwrap();
+        translate(&mut translator).await;
+        translator.code()
+    }
+
+    #[tokio::test]
+    async fn test_main_function() {
+        let code = entry_point(
+            "fn my_main(argc: i32, argv: *mut *mut libc::c_char) -> i32 { unsafe { **argv as i32 + argc } }",
+        )
+        .await;
+        assert!(compiler::type_check(&code).unwrap().passed(), "{}", code);
+        assert!(code.contains("std::env::args_os()"), "{}", code);
+        assert!(
+            code.contains("exit(my_main(args.len() as _, ptrs.as_mut_ptr() as _) as i32)"),
+            "{}",
+            code
+        );
+
+        let code =
+            entry_point("fn my_main(args: &[String]) { println!(\"{}\", args.len()); }").await;
+        assert!(compiler::type_check(&code).unwrap().passed(), "{}", code);
+        assert!(code.contains("my_main(&args);"), "{}", code);
+
+        let code = entry_point(
+            "fn my_main(argc: i32, argv: *mut *mut libc::c_char, envp: *mut *mut libc::c_char) -> i32 { argc }",
+        )
+        .await;
+        assert!(code.ends_with("fn main() {}"), "{}", code);
+    }
+
+    #[tokio::test]
+    async fn test_hand_written_helpers() {
+        let program = Program::from_code("int larger(int a, int b) { return a > b ? a : b; }");
+        let mut translator = Translator::new(&program, Box::<MockModel>::default(), config());
+        translator
+            .set_overrides(Overrides {
+                functions: BTreeMap::from([(
+                    "larger".to_string(),
+                    "fn larger(a: i32, b: i32) -> i32 { max(a, b) }\n\
+                 fn max(a: i32, b: i32) -> i32 { if a > b { a } else { b } }"
+                        .to_string(),
+                )]),
+                ..Default::default()
+            })
+            .unwrap();
+        translate(&mut translator).await;
+        let translated = &translator.inner.read().unwrap().translated_functions["larger"];
+        assert_eq!(translated.items.len(), 1);
+        assert_eq!(translated.helpers, ["max"]);
+        assert_eq!(translated.errors, 0);
+        assert!(
+            translated.code().contains("    fn max("),
+            "{}",
+            translated.code()
+        );
+    }
+
+    #[test]
+    fn test_override_keys() {
+        let program = Program::from_files(&[
+            ("a.c", "static int n; int f(void) { return n; }"),
+            ("b.c", "static int n; int g(void) { return n; }"),
+        ]);
+        let mut translator = Translator::new(&program, Box::new(MockModel::offline()), config());
+        let names = |key: &str| Overrides {
+            names: BTreeMap::from([(key.to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+        assert!(translator.set_overrides(names("f")).is_ok());
+        assert!(translator.set_overrides(names("n@b.c")).is_ok());
+        assert!(translator.set_overrides(names("h")).is_ok());
+        assert_eq!(
+            translator.set_overrides(names("n")).unwrap_err(),
+            "`n` is static in several files; use n@a.c or n@b.c"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_name_overrides() {
+        let program = Program::from_code(
+            "struct pair { int a; int b; }; int larger(int a, int b) { return a > b ? a : b; }",
+        );
+        let path = TempPath::new("names.json");
+        let overrides = |ty: &str, function: &str| Overrides {
+            types: BTreeMap::from([(
+                "pair".to_string(),
+                format!("struct {} {{ a: i32, b: i32 }}", ty),
+            )]),
+            functions: BTreeMap::from([(
+                "larger".to_string(),
+                format!("fn {}(a: i32, b: i32) -> i32 {{ a.max(b) }}", function),
+            )]),
+            ..Default::default()
+        };
+
+        let mut translator = Translator::new(&program, Box::<MockModel>::default(), config());
+        translator
+            .set_overrides(overrides("Pair", "larger"))
+            .unwrap();
+        translator.set_checkpoint(path.as_str().to_string());
+        translate(&mut translator).await;
+
+        let mut translator = Translator::new(&program, Box::new(MockModel::offline()), config());
+        translator
+            .set_overrides(Overrides {
+                type_names: BTreeMap::from([("pair".to_string(), "Couple".to_string())]),
+                names: BTreeMap::from([("larger".to_string(), "bigger".to_string())]),
+                ..overrides("Couple", "bigger")
+            })
+            .unwrap();
+        translator.set_checkpoint(path.as_str().to_string());
+        assert!(translator.resume());
+        let pair = translator.custom_types[0];
+        assert_eq!(translator.new_type_names[&pair], "Couple");
+        assert_eq!(translator.new_term_names["larger"], "bigger");
+        translator.retranslate("larger").await;
+        assert!(translator.code().contains("fn bigger("));
+    }
+
+    #[tokio::test]
+    async fn test_resume_renames() {
+        let program = Program::from_code("int box(int x) { return x; }");
+        let path = TempPath::new("renames.json");
+
+        let mut translator = Translator::new(&program, Box::<MockModel>::default(), config());
+        translator.set_checkpoint(path.as_str().to_string());
+        translator.translate_names().await;
+        assert_eq!(translator.renames.len(), 1);
+
+        let mut translator = Translator::new(&program, Box::new(MockModel::offline()), config());
+        translator.set_checkpoint(path.as_str().to_string());
+        assert!(translator.resume());
+        assert_eq!(translator.renames.len(), 1);
+        assert_eq!(translator.renames[0].new_name, "my_box");
+    }
+}